@@ -83,6 +83,41 @@ fn get_miss<H: Map>(b: &mut criterion::Bencher, max: u32) {
     })
 }
 
+fn get_batch_hit(b: &mut criterion::Bencher, max: u32) {
+    let mut map = BytellHashMap::with_capacity(max as usize, FnvBuildHasher::default());
+    let mut rng = SmallRng::from_seed([0, 1, 1, 2, 3, 5, 8, 13, 21, 34, 55, 89, 144, 233, 3, 5]);
+    let mut numbers = (0..max).collect::<Vec<_>>();
+    rng.shuffle(&mut numbers);
+    for n in &numbers {
+        map.insert(*n, *n);
+    }
+    rng.shuffle(&mut numbers);
+    let keys = numbers.iter().collect::<Vec<_>>();
+    b.iter(|| {
+        for value in map.get_batch(&keys) {
+            black_box(value);
+        }
+    })
+}
+
+fn get_batch_miss(b: &mut criterion::Bencher, max: u32) {
+    let mut map = BytellHashMap::with_capacity(max as usize, FnvBuildHasher::default());
+    let mut rng = SmallRng::from_seed([0, 1, 1, 2, 3, 5, 8, 13, 21, 34, 55, 89, 144, 233, 3, 5]);
+    let mut numbers = (0..max).collect::<Vec<_>>();
+    rng.shuffle(&mut numbers);
+    for n in &numbers {
+        map.insert(2 * n, 2 * n);
+    }
+    rng.shuffle(&mut numbers);
+    let misses = numbers.iter().map(|n| 2 * n + 1).collect::<Vec<_>>();
+    let keys = misses.iter().collect::<Vec<_>>();
+    b.iter(|| {
+        for value in map.get_batch(&keys) {
+            black_box(value);
+        }
+    })
+}
+
 fn insert<H: Map>(b: &mut criterion::Bencher, max: u32) {
     let mut map = H::with_capacity(16);
     let mut rng = SmallRng::from_seed([0, 1, 1, 2, 3, 5, 8, 13, 21, 34, 55, 89, 144, 233, 3, 5]);
@@ -118,12 +153,14 @@ fn comparisons(c: &mut Criterion) {
     c.bench(
         "compare/get/hit",
         ParameterizedBenchmark::new("bytell-hash-map", |b, size| get_hit::<BytellHashMap>(b, *size), checks.clone())
+            .with_function("bytell-hash-map-batch", |b, size| get_batch_hit(b, *size))
             .with_function("hash-map", |b, size| get_hit::<HashMap>(b, *size))
             .throughput(|n| Throughput::Elements(*n)),
     );
     c.bench(
         "compare/get/miss",
         ParameterizedBenchmark::new("bytell-hash-map", |b, size| get_miss::<BytellHashMap>(b, *size), checks.clone())
+            .with_function("bytell-hash-map-batch", |b, size| get_batch_miss(b, *size))
             .with_function("hash-map", |b, size| get_miss::<HashMap>(b, *size))
             .throughput(|n| Throughput::Elements(*n)),
     );
@@ -0,0 +1,58 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate bytell_hash_map;
+
+use std::collections::HashMap as StdHashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{BuildHasher, BuildHasherDefault};
+
+use bytell_hash_map::{BuildColliding, HashMap, HashMapBuilder};
+
+// The first byte picks the map configuration, every following pair is an operation and a key.
+fuzz_target!(|data: &[u8]| {
+    let (&config, ops) = match data.split_first() {
+        Some(split) => split,
+        None => return,
+    };
+    if config & 1 == 0 {
+        replay(build(config, BuildHasherDefault::<DefaultHasher>::default()), ops);
+    } else {
+        replay(build(config, BuildColliding::default()), ops);
+    }
+});
+
+fn build<H: BuildHasher>(config: u8, hasher: H) -> HashMap<u8, u32, H> {
+    let builder = HashMapBuilder::new(hasher).store_hashes(config & 2 != 0);
+    let builder = if config & 4 != 0 { builder.incremental_resize(1) } else { builder };
+    // Short jumps are only combined with proper hashes, weak ones could need ever larger tables
+    let builder = if config & 9 == 8 { builder.max_jump_index(1 + config / 16) } else { builder };
+    builder.build()
+}
+
+fn replay<H: BuildHasher>(mut map: HashMap<u8, u32, H>, ops: &[u8]) {
+    let mut model = StdHashMap::new();
+    for (step, op) in ops.chunks(2).enumerate() {
+        let key = op.get(1).cloned().unwrap_or(0);
+        let value = step as u32;
+        match op[0] % 8 {
+            0..=2 => assert_eq!(model.insert(key, value).map(|v| (key, v)), map.insert(key, value)),
+            3 | 4 => assert_eq!(model.remove(&key).map(|v| (key, v)), map.remove(&key)),
+            5 => assert_eq!(model.get(&key), map.get(&key)),
+            6 => {
+                map.retain(|k, _| k % 3 != key % 3);
+                model.retain(|k, _| k % 3 != key % 3);
+            },
+            _ => {
+                map.shrink_to_fit();
+            },
+        }
+        if let Err(error) = map.validate() {
+            panic!("{} after step {}", error, step);
+        }
+        assert_eq!(model.len(), map.len());
+    }
+    for (k, v) in &model {
+        assert_eq!(Some(v), map.get(k));
+    }
+}
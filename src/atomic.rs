@@ -0,0 +1,421 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::cmp;
+use core::hash::{BuildHasher, Hash};
+use core::hint;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicU8, AtomicUsize, Ordering};
+
+use Equivalent;
+
+// Metadata of slots that hold no entry. Full slots keep the top seven bits of the hash instead,
+// so that most other keys are skipped without looking at their entry.
+const EMPTY: u8 = 0xFF;
+// Claimed by an insertion that has not published its entry yet
+const RESERVED: u8 = 0xFE;
+// Empty slot of a table that is being migrated, insertions have to go to the next table
+const MOVED: u8 = 0xFD;
+
+// Slots migrated at a time by a thread helping with a resize
+const MIGRATION_CHUNK: usize = 256;
+
+// A table of its own rather than a concurrent version of the chained map: chains cannot be
+// relinked without locking, so slots are found by plain triangular probing and claimed by a CAS
+// on their metadata byte. Entries are boxed and never move or get removed, so references to them
+// live as long as the map. Tables that have been migrated are kept around for threads still
+// reading them and freed together with the map.
+// Lookups never wait, but insertions are not lock-free: they spin on slots claimed by another
+// insertion until its entry is published, and on chunks of a resize other threads have taken.
+// Neither runs user code, so the waits are short unless a thread is descheduled in between.
+pub struct AtomicInsertOnlyMap<K, V, H> {
+    table: AtomicPtr<Table<K, V>>,
+    // Oldest table, the others are reached through it
+    first: *mut Table<K, V>,
+    len: AtomicUsize,
+    hasher: H
+}
+
+unsafe impl<K: Send, V: Send, H: Send> Send for AtomicInsertOnlyMap<K, V, H> {}
+
+unsafe impl<K: Send + Sync, V: Send + Sync, H: Sync> Sync for AtomicInsertOnlyMap<K, V, H> {}
+
+struct Node<K, V> {
+    hash: u64,
+    key: K,
+    value: V
+}
+
+struct Table<K, V> {
+    meta: Box<[AtomicU8]>,
+    nodes: Box<[AtomicPtr<Node<K, V>>]>,
+    // Slots claimed so far, the table is grown once three quarters of them are
+    claimed: AtomicUsize,
+    next: AtomicPtr<Table<K, V>>,
+    // Start of the next chunk a helping thread takes, and number of slots already migrated
+    migrating: AtomicUsize,
+    migrated: AtomicUsize
+}
+
+impl<K, V> Table<K, V> {
+    fn new(slots: usize) -> Self {
+        Table {
+            meta: (0..slots).map(|_| AtomicU8::new(EMPTY)).collect::<Vec<_>>().into_boxed_slice(),
+            nodes: (0..slots).map(|_| AtomicPtr::new(ptr::null_mut())).collect::<Vec<_>>().into_boxed_slice(),
+            claimed: AtomicUsize::new(0),
+            next: AtomicPtr::new(ptr::null_mut()),
+            migrating: AtomicUsize::new(0),
+            migrated: AtomicUsize::new(0)
+        }
+    }
+
+    fn slots(&self) -> usize {
+        self.meta.len()
+    }
+
+    fn is_full(&self) -> bool {
+        4 * self.claimed.load(Ordering::Relaxed) >= 3 * self.slots()
+    }
+
+    // Visits every slot once, as triangular numbers modulo a power of two cover all of them.
+    fn probe(&self, hash: u64) -> Probe {
+        Probe {
+            pos: hash as usize & (self.slots() - 1),
+            step: 0,
+            mask: self.slots() - 1
+        }
+    }
+
+    // Places an entry of the table being migrated. Only migrating threads insert into a table
+    // before its migration is finished, and they never carry the same entry, so any empty slot
+    // will do.
+    unsafe fn place(&self, node: *mut Node<K, V>) {
+        let hash = (*node).hash;
+        for pos in self.probe(hash) {
+            let meta = &self.meta[pos];
+            if meta.compare_exchange(EMPTY, RESERVED, Ordering::Acquire, Ordering::Relaxed).is_ok() {
+                self.nodes[pos].store(node, Ordering::Relaxed);
+                meta.store(fragment(hash), Ordering::Release);
+                self.claimed.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        }
+        unreachable!("table grown for migration has no empty slot");
+    }
+
+    // Seals an empty slot or copies its entry to the next table.
+    unsafe fn migrate_slot(&self, next: &Table<K, V>, pos: usize) {
+        let meta = &self.meta[pos];
+        loop {
+            match meta.load(Ordering::Acquire) {
+                EMPTY => if meta.compare_exchange(EMPTY, MOVED, Ordering::Relaxed, Ordering::Relaxed).is_ok() {
+                    return;
+                },
+                RESERVED => hint::spin_loop(),
+                _ => {
+                    next.place(self.nodes[pos].load(Ordering::Relaxed));
+                    return;
+                },
+            }
+        }
+    }
+}
+
+struct Probe {
+    pos: usize,
+    step: usize,
+    mask: usize
+}
+
+impl Iterator for Probe {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        if self.step > self.mask {
+            return None;
+        }
+        let pos = self.pos;
+        self.step += 1;
+        self.pos = (self.pos + self.step) & self.mask;
+        Some(pos)
+    }
+}
+
+fn fragment(hash: u64) -> u8 {
+    (hash >> 57) as u8
+}
+
+impl<K, V, H> Default for AtomicInsertOnlyMap<K, V, H>
+    where K: Hash + PartialEq,
+          H: BuildHasher + Default
+{
+    fn default() -> Self {
+        Self::with_hasher(H::default())
+    }
+}
+
+impl<K, V, H> AtomicInsertOnlyMap<K, V, H>
+    where K: Hash + PartialEq,
+          H: BuildHasher
+{
+    pub fn with_hasher(hasher: H) -> Self {
+        Self::with_capacity_and_hasher(0, hasher)
+    }
+
+    pub fn with_capacity_and_hasher(capacity: usize, hasher: H) -> Self {
+        let slots = cmp::max(16, (capacity + capacity / 3 + 1).next_power_of_two());
+        let table = Box::into_raw(Box::new(Table::new(slots)));
+        AtomicInsertOnlyMap {
+            table: AtomicPtr::new(table),
+            first: table,
+            len: AtomicUsize::new(0),
+            hasher,
+        }
+    }
+
+    // Never waits for other threads. Entries being inserted at the same time may or may not be
+    // seen.
+    pub fn get<Q>(&self, key: &Q) -> Option<&V>
+        where Q: ?Sized + Hash + Equivalent<K>
+    {
+        let hash = self.hash(key); // NOTE: Possible panic
+        let mut table = self.table();
+        loop {
+            for pos in table.probe(hash) {
+                match table.meta[pos].load(Ordering::Acquire) {
+                    // An entry that is not there yet cannot be farther along the probe either.
+                    EMPTY => return None,
+                    MOVED => break,
+                    meta if meta == fragment(hash) => unsafe {
+                        let node = &*table.nodes[pos].load(Ordering::Relaxed);
+                        if node.hash == hash && key.equivalent(&node.key) { // NOTE: Possible panic
+                            return Some(&node.value);
+                        }
+                    },
+                    _ => {},
+                }
+            }
+            table = unsafe { table.next.load(Ordering::Acquire).as_ref()? };
+        }
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+        where Q: ?Sized + Hash + Equivalent<K>
+    {
+        self.get(key).is_some()
+    }
+
+    // Gives the value of the key, inserting `value` if it is missing. When several threads insert
+    // the same key at once, all of them get the value of the one that came first.
+    pub fn get_or_insert(&self, key: K, value: V) -> &V {
+        self.get_or_insert_with(key, || value)
+    }
+
+    pub fn get_or_insert_with<F>(&self, key: K, f: F) -> &V
+        where F: FnOnce() -> V
+    {
+        let hash = self.hash(&key); // NOTE: Possible panic
+        let mut key = Some(key);
+        let mut f = Some(f);
+        // Made once the key is known to be missing, so that `f` is not called needlessly
+        let mut node: *mut Node<K, V> = ptr::null_mut();
+        let mut table = self.table();
+        'tables: loop {
+            if !table.next.load(Ordering::Acquire).is_null() {
+                table = self.migrate(table);
+                continue;
+            }
+            if table.is_full() {
+                table = self.grow(table);
+                continue;
+            }
+            for pos in table.probe(hash) {
+                let meta = &table.meta[pos];
+                loop {
+                    match meta.load(Ordering::Acquire) {
+                        EMPTY => unsafe {
+                            if node.is_null() {
+                                let value = f.take().unwrap()(); // NOTE: Possible panic
+                                node = Box::into_raw(Box::new(Node {
+                                    hash,
+                                    key: key.take().unwrap(),
+                                    value
+                                }));
+                            }
+                            if meta.compare_exchange(EMPTY, RESERVED, Ordering::Acquire, Ordering::Relaxed).is_ok() {
+                                table.nodes[pos].store(node, Ordering::Relaxed);
+                                meta.store(fragment(hash), Ordering::Release);
+                                table.claimed.fetch_add(1, Ordering::Relaxed);
+                                self.len.fetch_add(1, Ordering::Relaxed);
+                                return &(*node).value;
+                            }
+                        },
+                        // The entry is published right after claiming, and could be this key,
+                        // so this blocks until it is.
+                        RESERVED => hint::spin_loop(),
+                        MOVED => continue 'tables,
+                        other => unsafe {
+                            if other == fragment(hash) {
+                                let found = &*table.nodes[pos].load(Ordering::Relaxed);
+                                let ours = if node.is_null() { key.as_ref().unwrap() } else { &(*node).key };
+                                if found.hash == hash && found.key == *ours { // NOTE: Possible panic
+                                    if !node.is_null() {
+                                        drop(Box::from_raw(node)); // NOTE: Possible panic
+                                    }
+                                    return &found.value;
+                                }
+                            }
+                            break;
+                        },
+                    }
+                }
+            }
+            table = self.grow(table);
+        }
+    }
+
+    // Entries inserted at the same time may or may not be counted.
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn hasher(&self) -> &H {
+        &self.hasher
+    }
+
+    fn hash<Q: ?Sized + Hash>(&self, key: &Q) -> u64 {
+        self.hasher.hash_one(key)
+    }
+}
+
+impl<K, V, H> AtomicInsertOnlyMap<K, V, H> {
+    fn table(&self) -> &Table<K, V> {
+        unsafe { &*self.table.load(Ordering::Acquire) }
+    }
+
+    // Starts a migration to a table twice as large, unless another thread already has.
+    fn grow(&self, table: &Table<K, V>) -> &Table<K, V> {
+        if table.next.load(Ordering::Acquire).is_null() {
+            let next = Box::into_raw(Box::new(Table::new(2 * table.slots())));
+            if table.next.compare_exchange(ptr::null_mut(), next, Ordering::AcqRel, Ordering::Acquire).is_err() {
+                unsafe { drop(Box::from_raw(next)); }
+            }
+        }
+        self.migrate(table)
+    }
+
+    // Migrates chunks of the table until none are left, then waits for the chunks other threads
+    // took. Migrating calls no user code, so every chunk taken is finished.
+    fn migrate(&self, table: &Table<K, V>) -> &Table<K, V> {
+        let next = unsafe { &*table.next.load(Ordering::Acquire) };
+        loop {
+            let start = table.migrating.fetch_add(MIGRATION_CHUNK, Ordering::Relaxed);
+            if start >= table.slots() {
+                break;
+            }
+            let end = cmp::min(start + MIGRATION_CHUNK, table.slots());
+            for pos in start..end {
+                unsafe { table.migrate_slot(next, pos); }
+            }
+            table.migrated.fetch_add(end - start, Ordering::Release);
+        }
+        while table.migrated.load(Ordering::Acquire) < table.slots() {
+            hint::spin_loop();
+        }
+        let _ = self.table.compare_exchange(table as *const _ as *mut _, next as *const _ as *mut _, Ordering::AcqRel, Ordering::Acquire);
+        next
+    }
+}
+
+impl<K, V, H> Drop for AtomicInsertOnlyMap<K, V, H> {
+    fn drop(&mut self) {
+        let mut table = self.first;
+        while !table.is_null() {
+            unsafe {
+                let boxed = Box::from_raw(table);
+                table = boxed.next.load(Ordering::Relaxed);
+                // Migrations always finish, so every entry ends up in the last table.
+                if table.is_null() {
+                    for (meta, node) in boxed.meta.iter().zip(boxed.nodes.iter()) {
+                        if meta.load(Ordering::Relaxed) < MOVED {
+                            drop(Box::from_raw(node.load(Ordering::Relaxed))); // NOTE: Possible panic
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn atomic_insert_only_map_works() {
+    use std::sync::Arc;
+
+    let value = Arc::new(());
+    let map = AtomicInsertOnlyMap::with_hasher(::fnv::FnvBuildHasher::default());
+    for n in 0..10000u32 {
+        assert_eq!(n, map.get_or_insert(n, (n, value.clone())).0);
+    }
+    assert_eq!(10000, map.len());
+    assert_eq!(1, map.get_or_insert(1, (2, value.clone())).0);
+    assert_eq!(Some(5), map.get(&5).map(|v| v.0));
+    assert!(map.get(&10000).is_none() && !map.contains_key(&10001));
+    assert_eq!(7, map.get_or_insert_with(7, || panic!("value is there")).0);
+    assert!(::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| {
+        map.get_or_insert_with(10000, || panic!("value is made"));
+    })).is_err());
+    assert_eq!(10000, map.len());
+    assert_eq!(10001, Arc::strong_count(&value));
+    drop(map);
+    assert_eq!(1, Arc::strong_count(&value));
+
+    // Every key has the same home slot
+    let map = AtomicInsertOnlyMap::with_capacity_and_hasher(100, ::std::hash::BuildHasherDefault::<Constant>::default());
+    for n in 0..1000u32 {
+        map.get_or_insert(n, n);
+    }
+    assert!((0..1000).all(|n| map.get(&n) == Some(&n)));
+
+    #[derive(Default)]
+    struct Constant;
+
+    impl ::core::hash::Hasher for Constant {
+        fn finish(&self) -> u64 {
+            0
+        }
+
+        fn write(&mut self, _: &[u8]) {}
+    }
+}
+
+#[test]
+fn concurrent_interning_works() {
+    use std::sync::Arc;
+    use std::thread;
+
+    let threads = 8;
+    let max = 20000u64;
+    let map = Arc::new(AtomicInsertOnlyMap::with_hasher(::fnv::FnvBuildHasher::default()));
+    let handles = (0..threads)
+        .map(|thread| {
+            let map = map.clone();
+            thread::spawn(move || {
+                // Threads start at different keys, so that they race both on inserts and resizes.
+                let mut seen = vec![0; max as usize];
+                for n in (0..max).map(|n| (n + thread * max / threads) % max) {
+                    seen[n as usize] = map.get_or_insert(n, thread) as *const u64 as usize;
+                }
+                seen
+            })
+        })
+        .collect::<Vec<_>>();
+    let seen = handles.into_iter().map(|handle| handle.join().unwrap()).collect::<Vec<_>>();
+    assert_eq!(max as usize, map.len());
+    for n in 0..max {
+        let value = map.get(&n).unwrap() as *const u64 as usize;
+        assert!(seen.iter().all(|thread| thread[n as usize] == value));
+    }
+}
@@ -0,0 +1,142 @@
+use core::cmp;
+use core::hash::{BuildHasher, Hash};
+
+use simd::prefetch;
+use {cell_data, Allocator, Cell, Equivalent, Global, HashMap};
+
+// Keys hashed ahead of resolving them, enough to keep several cache misses in flight
+const GROUP: usize = 16;
+
+pub struct GetBatch<'a, K: 'a, V: 'a, H: 'a, Q: ?Sized + 'a, A: Allocator + 'a = Global> {
+    map: &'a HashMap<K, V, H, A>,
+    keys: &'a [&'a Q],
+    hashes: [usize; GROUP],
+    // Keys before this one have been hashed and their cells prefetched
+    hashed: usize,
+    next: usize
+}
+
+impl<'a, K: 'a, V: 'a, H: 'a, Q: ?Sized + 'a, A: Allocator + 'a> GetBatch<'a, K, V, H, Q, A>
+    where K: Hash + PartialEq,
+          H: BuildHasher,
+          Q: Hash + Equivalent<K>
+{
+    fn hash_group(&mut self) {
+        let end = cmp::min(self.hashed + GROUP, self.keys.len());
+        for index in self.hashed..end {
+            let hash = self.map.hash(self.keys[index]) as usize; // NOTE: Possible panic
+            self.hashes[index % GROUP] = hash;
+            unsafe {
+                prefetch_cell(self.map.ptr, self.map.capacity, hash);
+                if let Some(ref old) = self.map.old {
+                    prefetch_cell(old.ptr, old.capacity, hash);
+                }
+            }
+        }
+        self.hashed = end;
+    }
+}
+
+impl<'a, K: 'a, V: 'a, H: 'a, Q: ?Sized + 'a, A: Allocator + 'a> Iterator for GetBatch<'a, K, V, H, Q, A>
+    where K: Hash + PartialEq,
+          H: BuildHasher,
+          Q: Hash + Equivalent<K>
+{
+    type Item = Option<&'a V>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next == self.keys.len() {
+            return None;
+        }
+        if self.next == self.hashed {
+            self.hash_group();
+        }
+        let key = self.keys[self.next];
+        let hash = self.hashes[self.next % GROUP];
+        self.next += 1;
+        unsafe {
+            Some(self.map.find(hash, |k| key.equivalent(k)).map(|bucket| &(*bucket).value)) // NOTE: Possible panic
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.keys.len() - self.next;
+        (remaining, Some(remaining))
+    }
+}
+
+pub struct ContainsBatch<'a, K: 'a, V: 'a, H: 'a, Q: ?Sized + 'a, A: Allocator + 'a = Global>(GetBatch<'a, K, V, H, Q, A>);
+
+impl<'a, K: 'a, V: 'a, H: 'a, Q: ?Sized + 'a, A: Allocator + 'a> Iterator for ContainsBatch<'a, K, V, H, Q, A>
+    where K: Hash + PartialEq,
+          H: BuildHasher,
+          Q: Hash + Equivalent<K>
+{
+    type Item = bool;
+
+    fn next(&mut self) -> Option<bool> {
+        self.0.next().map(|value| value.is_some())
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.0.size_hint()
+    }
+}
+
+impl<K, V, H, A> HashMap<K, V, H, A>
+    where K: Hash + PartialEq,
+          H: BuildHasher,
+          A: Allocator
+{
+    // Looks up the keys in order. Keys are hashed a group at a time and their cells prefetched,
+    // so that the memory accesses of a group overlap instead of waiting on each other.
+    pub fn get_batch<'a, Q>(&'a self, keys: &'a [&'a Q]) -> GetBatch<'a, K, V, H, Q, A>
+        where Q: ?Sized + Hash + Equivalent<K>
+    {
+        GetBatch {
+            map: self,
+            keys,
+            hashes: [0; GROUP],
+            hashed: 0,
+            next: 0
+        }
+    }
+
+    pub fn contains_batch<'a, Q>(&'a self, keys: &'a [&'a Q]) -> ContainsBatch<'a, K, V, H, Q, A>
+        where Q: ?Sized + Hash + Equivalent<K>
+    {
+        ContainsBatch(self.get_batch(keys))
+    }
+}
+
+// Both the metadata and the bucket of the home slot are needed to resolve a key.
+unsafe fn prefetch_cell<K, V>(ptr: *mut Cell<K, V>, capacity: usize, hash: usize) {
+    let (meta_ptr, data_ptr) = cell_data(ptr, capacity, hash);
+    prefetch(meta_ptr);
+    prefetch(data_ptr);
+}
+
+#[test]
+fn batched_lookups_work() {
+    use std::string::ToString;
+    use std::vec::Vec;
+
+    let max = 10000;
+    for mut map in ::HashMapBuilder::new(::fnv::FnvBuildHasher::default()).build_both() {
+        for n in 0..max {
+            map.insert(2 * n, n);
+        }
+        let owned = (0..2 * max + 5).rev().collect::<Vec<_>>();
+        let keys = owned.iter().collect::<Vec<_>>();
+        let batch = map.get_batch(&keys);
+        assert_eq!((keys.len(), Some(keys.len())), batch.size_hint());
+        assert_eq!(keys.iter().map(|k| map.get(*k)).collect::<Vec<_>>(), batch.collect::<Vec<_>>());
+        assert_eq!(max, map.contains_batch(&keys).filter(|&found| found).count());
+        assert_eq!(None, map.get_batch::<usize>(&[]).next());
+    }
+
+    let mut map = ::HashMap::with_hasher(::fnv::FnvBuildHasher::default());
+    map.insert("a".to_string(), 1);
+    map.insert("b".to_string(), 2);
+    assert_eq!(vec![Some(&2), None, Some(&1)], map.get_batch::<str>(&["b", "c", "a"]).collect::<Vec<_>>());
+}
@@ -0,0 +1,270 @@
+use core::hash::{BuildHasher, Hash};
+
+use {Allocator, Global, HashMap, TryReserveError, BLOCK_SIZE, JUMP_DISTANCES, MAX_LOAD_FACTOR};
+
+// How a map decides when and how much to grow.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Policy {
+    pub(crate) max_load_factor: f32,
+    pub(crate) growth_factor: usize,
+    pub(crate) max_jump: u8,
+    // Cells migrated per operation during an incremental resize, zero resizes all at once
+    pub(crate) migrate_cells: usize,
+    pub(crate) store_hashes: bool,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy {
+            max_load_factor: MAX_LOAD_FACTOR,
+            growth_factor: 2,
+            max_jump: (JUMP_DISTANCES.len() - 1) as u8,
+            migrate_cells: 0,
+            store_hashes: false
+        }
+    }
+}
+
+impl Policy {
+    // Number of items given amount of cells can hold without exceeding the maximum load factor
+    pub(crate) fn items_for(&self, cells: usize) -> usize {
+        (BLOCK_SIZE.saturating_mul(cells) as f64 * self.max_load_factor as f64) as usize
+    }
+
+    // Number of cells needed to hold given amount of items without exceeding the maximum load factor
+    pub(crate) fn cells_for(&self, items: usize) -> Result<usize, TryReserveError> {
+        let cells = items as f64 / self.max_load_factor as f64 / BLOCK_SIZE as f64;
+        if cells >= usize::MAX as f64 {
+            return Err(TryReserveError::CapacityOverflow);
+        }
+        let whole = cells as usize;
+        let cells = if (whole as f64) < cells { whole + 1 } else { whole };
+        let mut cells = cells.max(1).checked_next_power_of_two().ok_or(TryReserveError::CapacityOverflow)?;
+        // Rounding of the estimate can leave it one short
+        while self.items_for(cells) < items {
+            cells = cells.checked_mul(2).ok_or(TryReserveError::CapacityOverflow)?;
+        }
+        Ok(cells)
+    }
+
+    pub(crate) fn grown(&self, cells: usize) -> Result<usize, TryReserveError> {
+        cells.checked_mul(self.growth_factor).ok_or(TryReserveError::CapacityOverflow)
+    }
+}
+
+pub struct HashMapBuilder<H, A: Allocator = Global> {
+    hasher: H,
+    alloc: A,
+    capacity: usize,
+    policy: Policy,
+}
+
+impl<H> HashMapBuilder<H> {
+    pub fn new(hasher: H) -> Self {
+        Self::new_in(hasher, Global)
+    }
+}
+
+impl<H, A> Default for HashMapBuilder<H, A>
+    where H: Default,
+          A: Allocator + Default
+{
+    fn default() -> Self {
+        Self::new_in(H::default(), A::default())
+    }
+}
+
+impl<H, A: Allocator> HashMapBuilder<H, A> {
+    pub fn new_in(hasher: H, alloc: A) -> Self {
+        HashMapBuilder {
+            hasher,
+            alloc,
+            capacity: 0,
+            policy: Policy::default()
+        }
+    }
+
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    // Lower values trade memory for shorter chains.
+    pub fn max_load_factor(mut self, max_load_factor: f32) -> Self {
+        assert!(max_load_factor > 0. && max_load_factor <= 1., "maximum load factor has to be in (0, 1]");
+        self.policy.max_load_factor = max_load_factor;
+        self
+    }
+
+    pub fn growth_factor(mut self, growth_factor: usize) -> Self {
+        assert!(growth_factor >= 2 && growth_factor.is_power_of_two(), "growth factor has to be a power of two greater than one");
+        self.policy.growth_factor = growth_factor;
+        self
+    }
+
+    // Inserting grows the map when no empty slot is found within this many jump distances.
+    // Lower values keep chains compact at the cost of growing earlier.
+    pub fn max_jump_index(mut self, max_jump: u8) -> Self {
+        assert!(max_jump >= 1 && (max_jump as usize) < JUMP_DISTANCES.len(), "maximum jump index has to be in [1, 125]");
+        self.policy.max_jump = max_jump;
+        self
+    }
+
+    // Growing keeps the old cells around and moves `cells_per_operation` cells of them over on
+    // each following insertion or removal instead of rehashing everything at once.
+    // Lookups search both tables until the move is done.
+    pub fn incremental_resize(mut self, cells_per_operation: usize) -> Self {
+        assert!(cells_per_operation >= 1, "incremental resizing has to move at least one cell per operation");
+        self.policy.migrate_cells = cells_per_operation;
+        self
+    }
+
+    // Keeps the hash of every key next to it, so that relocating buckets and growing never hash
+    // keys again. Costs a `usize` per slot.
+    pub fn store_hashes(mut self, store_hashes: bool) -> Self {
+        self.policy.store_hashes = store_hashes;
+        self
+    }
+
+    pub fn build<K, V>(self) -> HashMap<K, V, H, A>
+        where K: Hash + PartialEq,
+              H: BuildHasher
+    {
+        HashMap::with_policy_in(self.capacity, self.policy, self.hasher, self.alloc)
+    }
+
+    pub fn try_build<K, V>(self) -> Result<HashMap<K, V, H, A>, TryReserveError>
+        where K: Hash + PartialEq,
+              H: BuildHasher
+    {
+        HashMap::try_with_policy_in(self.capacity, self.policy, self.hasher, self.alloc)
+    }
+}
+
+#[cfg(test)]
+impl<H: BuildHasher + Clone> HashMapBuilder<H> {
+    // Builds the map once resizing all at once and once incrementally, for tests covering both.
+    pub(crate) fn build_both<K: Hash + PartialEq, V>(self) -> [HashMap<K, V, H>; 2] {
+        let incremental = HashMapBuilder {
+            hasher: self.hasher.clone(),
+            alloc: Global,
+            capacity: self.capacity,
+            policy: self.policy
+        };
+        [self.build(), incremental.incremental_resize(1).build()]
+    }
+}
+
+#[test]
+fn builder_policy_works() {
+    let max = 10000;
+    let mut map = HashMapBuilder::new(::fnv::FnvBuildHasher::default())
+        .capacity(100)
+        .max_load_factor(0.5)
+        .growth_factor(4)
+        .max_jump_index(40)
+        .build();
+    assert!(map.capacity() >= 100);
+    assert_eq!(16, map.capacity);
+    for n in 0..max {
+        map.insert(n, n);
+        assert!(map.len() <= map.capacity());
+        assert!(map.capacity.trailing_zeros() % 2 == 0);
+        assert!(map.len() as f32 <= (BLOCK_SIZE * map.capacity) as f32 * 0.5);
+    }
+    for n in 0..max {
+        assert_eq!(Some(&n), map.get(&n));
+    }
+}
+
+#[test]
+fn shrinking_works() {
+    let max = 10000;
+    let mut map = HashMapBuilder::new(::fnv::FnvBuildHasher::default())
+        .max_load_factor(0.5)
+        .build();
+    for n in 0..max {
+        map.insert(n, n);
+    }
+    map.retain(|k, _| *k < 100);
+    let capacity = map.capacity();
+    map.shrink_to(1000);
+    assert!(map.capacity() >= 1000 && map.capacity() < capacity);
+    map.shrink_to_fit();
+    assert!(map.capacity() >= 100 && map.capacity() < 200);
+    assert_eq!(100, map.len());
+    for n in 0..100 {
+        assert_eq!(Some(&n), map.get(&n));
+    }
+    map.reserve(1000);
+    assert!(map.capacity() >= 1100);
+}
+
+#[test]
+fn incremental_resizing_works() {
+    let max = 10000;
+    let mut map = HashMapBuilder::new(::fnv::FnvBuildHasher::default())
+        .incremental_resize(1)
+        .build();
+    let mut migrating = false;
+    for n in 0..max {
+        assert_eq!(None, map.insert(n, n));
+        migrating |= map.old.is_some();
+        if n % 7 == 0 {
+            assert_eq!(Some((n / 2, n / 2)), map.remove(&(n / 2)));
+            assert_eq!(None, map.insert(n / 2, n / 2));
+        }
+        assert_eq!(n + 1, map.len());
+        assert_eq!(Some(&(n / 3)), map.get(&(n / 3)));
+    }
+    assert!(migrating);
+    assert_eq!(max, map.iter().count());
+    for n in 0..max {
+        assert_eq!(Some(&n), map.get(&n));
+        *map.get_mut(&n).unwrap() += 1;
+    }
+    for n in (0..max).filter(|n| n % 2 == 0) {
+        assert_eq!(Some((n, n + 1)), map.remove(&n));
+    }
+    assert_eq!(max / 2, map.len());
+    map.reserve(4 * max);
+    assert!(map.old.is_none());
+    for n in 0..max {
+        assert_eq!(if n % 2 == 0 { None } else { Some(n + 1) }, map.get(&n).cloned());
+    }
+}
+
+#[test]
+fn stored_hashes_work() {
+    use core::hash::Hasher;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static HASHED: AtomicUsize = AtomicUsize::new(0);
+
+    #[derive(PartialEq, Eq, Debug)]
+    struct Counted(u64);
+
+    impl Hash for Counted {
+        fn hash<S: Hasher>(&self, state: &mut S) {
+            HASHED.fetch_add(1, Ordering::SeqCst);
+            self.0.hash(state);
+        }
+    }
+
+    let max = 10000;
+    for mut map in HashMapBuilder::new(::fnv::FnvBuildHasher::default()).store_hashes(true).build_both() {
+        HASHED.store(0, Ordering::SeqCst);
+        for n in 0..max {
+            map.insert(Counted(n), n);
+        }
+        for n in (0..max).filter(|n| n % 3 == 0) {
+            assert_eq!(Some(n), map.remove(&Counted(n)).map(|(_, v)| v));
+        }
+        map.shrink_to_fit();
+        // Only the keys given to `insert` and `remove` were hashed
+        assert_eq!((max + max.div_ceil(3)) as usize, HASHED.load(Ordering::SeqCst));
+        for n in 0..max {
+            assert_eq!(if n % 3 == 0 { None } else { Some(&n) }, map.get(&Counted(n)));
+        }
+    }
+}
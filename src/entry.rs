@@ -0,0 +1,145 @@
+use core::mem;
+use core::hash::{BuildHasher, Hash};
+
+use {Allocator, Bucket, Global, HashMap, Vacancy};
+
+pub enum Entry<'a, K: 'a, V: 'a, H: 'a, A: Allocator + 'a = Global> {
+    Occupied(OccupiedEntry<'a, K, V, H, A>),
+    Vacant(VacantEntry<'a, K, V, H, A>),
+}
+
+impl<'a, K: 'a, V: 'a, H: 'a, A: Allocator + 'a> Entry<'a, K, V, H, A>
+    where K: Hash + PartialEq,
+          H: BuildHasher
+{
+    pub fn or_insert(self, default: V) -> &'a mut V {
+        match self {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(default),
+        }
+    }
+
+    pub fn or_insert_with<F: FnOnce() -> V>(self, default: F) -> &'a mut V {
+        match self {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(default()),
+        }
+    }
+
+    pub fn or_insert_with_key<F: FnOnce(&K) -> V>(self, default: F) -> &'a mut V {
+        match self {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let value = default(entry.key());
+                entry.insert(value)
+            },
+        }
+    }
+
+    pub fn or_default(self) -> &'a mut V
+        where V: Default
+    {
+        self.or_insert_with(V::default)
+    }
+
+    pub fn and_modify<F: FnOnce(&mut V)>(self, f: F) -> Self {
+        match self {
+            Entry::Occupied(mut entry) => {
+                f(entry.get_mut());
+                Entry::Occupied(entry)
+            },
+            Entry::Vacant(entry) => Entry::Vacant(entry),
+        }
+    }
+
+    pub fn key(&self) -> &K {
+        match *self {
+            Entry::Occupied(ref entry) => entry.key(),
+            Entry::Vacant(ref entry) => entry.key(),
+        }
+    }
+}
+
+pub struct OccupiedEntry<'a, K: 'a, V: 'a, H: 'a, A: Allocator + 'a = Global> {
+    map: &'a mut HashMap<K, V, H, A>,
+    prev: usize,
+    pos: usize,
+}
+
+impl<'a, K: 'a, V: 'a, H: 'a, A: Allocator + 'a> OccupiedEntry<'a, K, V, H, A>
+    where K: Hash + PartialEq,
+          H: BuildHasher
+{
+    pub(crate) fn new(map: &'a mut HashMap<K, V, H, A>, prev: usize, pos: usize) -> Self {
+        OccupiedEntry {
+            map,
+            prev,
+            pos
+        }
+    }
+
+    pub fn key(&self) -> &K {
+        unsafe { &(*self.map.bucket_ptr(self.pos)).key }
+    }
+
+    pub fn get(&self) -> &V {
+        unsafe { &(*self.map.bucket_ptr(self.pos)).value }
+    }
+
+    pub fn get_mut(&mut self) -> &mut V {
+        unsafe { &mut (*self.map.bucket_ptr(self.pos)).value }
+    }
+
+    pub fn into_mut(self) -> &'a mut V {
+        unsafe { &mut (*self.map.bucket_ptr(self.pos)).value }
+    }
+
+    pub fn insert(&mut self, value: V) -> V {
+        mem::replace(self.get_mut(), value)
+    }
+
+    pub fn remove(self) -> V {
+        self.remove_entry().1
+    }
+
+    pub fn remove_entry(self) -> (K, V) {
+        let bucket = unsafe { self.map.remove_at(self.prev, self.pos) };
+        (bucket.key, bucket.value)
+    }
+}
+
+pub struct VacantEntry<'a, K: 'a, V: 'a, H: 'a, A: Allocator + 'a = Global> {
+    map: &'a mut HashMap<K, V, H, A>,
+    key: K,
+    hash: usize,
+    vacancy: Vacancy,
+}
+
+impl<'a, K: 'a, V: 'a, H: 'a, A: Allocator + 'a> VacantEntry<'a, K, V, H, A>
+    where K: Hash + PartialEq,
+          H: BuildHasher
+{
+    pub(crate) fn new(map: &'a mut HashMap<K, V, H, A>, key: K, hash: usize, vacancy: Vacancy) -> Self {
+        VacantEntry {
+            map,
+            key,
+            hash,
+            vacancy
+        }
+    }
+
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    pub fn into_key(self) -> K {
+        self.key
+    }
+
+    pub fn insert(self, value: V) -> &'a mut V {
+        unsafe {
+            let bucket = self.map.insert_vacant(self.hash, self.vacancy, Bucket::new(self.key, value));
+            &mut (*bucket).value
+        }
+    }
+}
@@ -0,0 +1,53 @@
+use core::borrow::Borrow;
+use core::hash::{Hash, Hasher};
+
+/// Key equivalence used by lookups.
+///
+/// Implemented for every `Q` that `K` can be borrowed as. Implement it for your own query types
+/// to look up composite keys without constructing an owned key, or use [`Composite`] for tuple
+/// keys.
+///
+/// A `Q` equivalent to a key has to hash exactly like that key, or lookups will miss it. Derived
+/// `Hash` implementations hash the fields in order, the same as a tuple of them.
+pub trait Equivalent<K: ?Sized> {
+    fn equivalent(&self, key: &K) -> bool;
+}
+
+impl<Q, K> Equivalent<K> for Q
+    where Q: ?Sized + Eq,
+          K: ?Sized + Borrow<Q>
+{
+    fn equivalent(&self, key: &K) -> bool {
+        *self == *key.borrow()
+    }
+}
+
+/// Looks up tuple keys by a tuple of borrowed parts, e.g. a `(String, u32)` key by
+/// `Composite((name, &id))` with `name: &str`.
+///
+/// Hashes like the tuple of the parts, which is the hash of the key as long as every part hashes
+/// like the key part it is equivalent to.
+#[derive(Clone, Copy, Debug)]
+pub struct Composite<T>(pub T);
+
+impl<T: Hash> Hash for Composite<T> {
+    fn hash<S: Hasher>(&self, state: &mut S) {
+        self.0.hash(state)
+    }
+}
+
+macro_rules! composite {
+    ($($q:ident $k:ident $n:tt),+) => {
+        impl<'a, $($q, $k),+> Equivalent<($($k,)+)> for Composite<($(&'a $q,)+)>
+            where $($q: ?Sized + Equivalent<$k>),+
+        {
+            fn equivalent(&self, key: &($($k,)+)) -> bool {
+                $((self.0).$n.equivalent(&key.$n))&&+
+            }
+        }
+    }
+}
+
+composite!(Q0 K0 0, Q1 K1 1);
+composite!(Q0 K0 0, Q1 K1 1, Q2 K2 2);
+composite!(Q0 K0 0, Q1 K1 1, Q2 K2 2, Q3 K3 3);
@@ -0,0 +1,364 @@
+use core::fmt;
+use core::hash::{BuildHasher, Hash};
+use core::marker::PhantomData;
+use core::mem;
+use core::ptr;
+#[cfg(feature = "std")]
+use core::slice;
+
+#[cfg(feature = "std")]
+use std::io;
+
+#[cfg(feature = "std")]
+use {Allocator, HashMap};
+use {cell_data, probe_cells, Bucket, Cell, Equivalent, BLOCK_SIZE};
+#[cfg(feature = "std")]
+use simd::BitMask;
+
+// Spells "BYTELLFZ" when read on a machine of the same endianness
+const MAGIC: u64 = 0x5a46_4c4c_4554_5942;
+// Bumped whenever the header, the cell layout or the way chains are laid out changes
+const VERSION: u64 = 1;
+
+// Hashers whose state can be written down, so that a frozen map can be searched after loading.
+pub trait FrozenHasher: BuildHasher {
+    // Tells hashers apart, opening a map frozen with another hasher fails.
+    const ID: u64;
+
+    fn seed(&self) -> u64;
+
+    fn from_seed(seed: u64) -> Self;
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FrozenError {
+    TooShort,
+    // Not a frozen map, or one written on a machine of the other endianness
+    BadMagic,
+    UnsupportedVersion(u64),
+    HasherMismatch,
+    // Key, value or cell sizes and alignments differ from the ones of the file
+    LayoutMismatch,
+    // Bytes do not start at the alignment of the header and the cells
+    Misaligned,
+}
+
+impl fmt::Display for FrozenError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FrozenError::TooShort => fmt.write_str("frozen map is truncated"),
+            FrozenError::BadMagic => fmt.write_str("bytes are not a frozen map"),
+            FrozenError::UnsupportedVersion(version) => write!(fmt, "frozen map has unsupported version {}", version),
+            FrozenError::HasherMismatch => fmt.write_str("frozen map was written with another hasher"),
+            FrozenError::LayoutMismatch => fmt.write_str("frozen map was written for other key or value types"),
+            FrozenError::Misaligned => fmt.write_str("frozen map is not aligned in memory"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl ::std::error::Error for FrozenError {}
+
+// Every field is a native endian u64, so the header has no padding.
+#[repr(C)]
+struct Header {
+    magic: u64,
+    version: u64,
+    hasher_id: u64,
+    seed: u64,
+    capacity: u64,
+    size: u64,
+    key_size: u64,
+    key_align: u64,
+    value_size: u64,
+    value_align: u64,
+    cell_size: u64,
+    // Hashes are truncated to it before finding the home slot
+    usize_size: u64
+}
+
+impl Header {
+    fn new<K, V>(hasher_id: u64, seed: u64, capacity: usize, size: usize) -> Self {
+        Header {
+            magic: MAGIC,
+            version: VERSION,
+            hasher_id,
+            seed,
+            capacity: capacity as u64,
+            size: size as u64,
+            key_size: mem::size_of::<K>() as u64,
+            key_align: mem::align_of::<K>() as u64,
+            value_size: mem::size_of::<V>() as u64,
+            value_align: mem::align_of::<V>() as u64,
+            cell_size: mem::size_of::<Cell<K, V>>() as u64,
+            usize_size: mem::size_of::<usize>() as u64
+        }
+    }
+}
+
+// Cells follow the header, starting at their own alignment.
+fn cells_offset<K, V>() -> usize {
+    let align = mem::align_of::<Cell<K, V>>();
+    (mem::size_of::<Header>() + align - 1) & !(align - 1)
+}
+
+#[cfg(feature = "std")]
+impl<K, V, H, A> HashMap<K, V, H, A>
+    where K: Hash + PartialEq + Copy,
+          V: Copy,
+          H: FrozenHasher,
+          A: Allocator
+{
+    // Writes the cells as they are in memory, behind a header describing them, so that
+    // `FrozenMap::open` can search them without copying or rehashing. Migration is finished
+    // first, as only the current cells are written. The file only opens on machines with the
+    // same endianness and pointer width.
+    pub fn freeze<W: io::Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        self.finish_migration();
+        let header = Header::new::<K, V>(H::ID, self.hasher.seed(), self.capacity, self.size);
+        unsafe {
+            writer.write_all(slice::from_raw_parts(&header as *const Header as *const u8, mem::size_of::<Header>()))?;
+        }
+        let padding = [0; 64];
+        writer.write_all(&padding[..cells_offset::<K, V>() - mem::size_of::<Header>()])?;
+        // Keys and values are written field by field into a zeroed cell, so that empty slots and
+        // the padding around keys and values are written as zeroes rather than whatever the
+        // memory held.
+        let mut cell = mem::MaybeUninit::<Cell<K, V>>::zeroed();
+        for index in 0..self.capacity {
+            unsafe {
+                let src = &*self.ptr.add(index);
+                let dst = cell.as_mut_ptr();
+                ptr::write_bytes(dst, 0, 1);
+                ptr::copy_nonoverlapping(&src.meta, &mut (*dst).meta, 1);
+                for slot in BitMask(src.meta.full_mask()) {
+                    let src_bucket = src.data.0.as_ptr().add(slot);
+                    let dst_bucket = (ptr::addr_of_mut!((*dst).data) as *mut Bucket<K, V>).add(slot);
+                    ptr::write(&mut (*dst_bucket).key, (*src_bucket).key);
+                    ptr::write(&mut (*dst_bucket).value, (*src_bucket).value);
+                }
+                writer.write_all(slice::from_raw_parts(dst as *const u8, mem::size_of::<Cell<K, V>>()))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Map searched in place in bytes written by `HashMap::freeze`, typically a memory mapped file.
+pub struct FrozenMap<'a, K: 'a, V: 'a, H> {
+    ptr: *mut Cell<K, V>,
+    capacity: usize,
+    size: usize,
+    hasher: H,
+    marker: PhantomData<&'a [Cell<K, V>]>
+}
+
+unsafe impl<'a, K: Sync, V: Sync, H: Send> Send for FrozenMap<'a, K, V, H> {}
+
+unsafe impl<'a, K: Sync, V: Sync, H: Sync> Sync for FrozenMap<'a, K, V, H> {}
+
+impl<'a, K: 'a, V: 'a, H> FrozenMap<'a, K, V, H>
+    where K: Hash + PartialEq + Copy,
+          V: Copy,
+          H: FrozenHasher
+{
+    /// Checks the header, but not the cells.
+    ///
+    /// # Safety
+    ///
+    /// The bytes have to be written by `HashMap::freeze` for the same key and value types and
+    /// must not have been changed since, as the cells are searched and keys and values are read
+    /// from them as they are. Keys and values must not hold pointers or references, which would
+    /// not be valid in another process.
+    pub unsafe fn open(bytes: &'a [u8]) -> Result<Self, FrozenError> {
+        let align = mem::align_of::<Cell<K, V>>().max(mem::align_of::<Header>());
+        if bytes.as_ptr() as usize & (align - 1) != 0 {
+            return Err(FrozenError::Misaligned);
+        }
+        if bytes.len() < mem::size_of::<Header>() {
+            return Err(FrozenError::TooShort);
+        }
+        let header = &*(bytes.as_ptr() as *const Header);
+        if header.magic != MAGIC {
+            return Err(FrozenError::BadMagic);
+        }
+        if header.version != VERSION {
+            return Err(FrozenError::UnsupportedVersion(header.version));
+        }
+        if header.hasher_id != H::ID {
+            return Err(FrozenError::HasherMismatch);
+        }
+        let expected = Header::new::<K, V>(H::ID, header.seed, header.capacity as usize, header.size as usize);
+        if (header.key_size, header.key_align, header.value_size, header.value_align, header.cell_size, header.usize_size)
+            != (expected.key_size, expected.key_align, expected.value_size, expected.value_align, expected.cell_size, expected.usize_size)
+        {
+            return Err(FrozenError::LayoutMismatch);
+        }
+        let capacity = header.capacity as usize;
+        if !capacity.is_power_of_two() || header.size > header.capacity.saturating_mul(BLOCK_SIZE as u64) {
+            return Err(FrozenError::LayoutMismatch);
+        }
+        let cells_len = capacity.checked_mul(mem::size_of::<Cell<K, V>>()).and_then(|len| len.checked_add(cells_offset::<K, V>()));
+        match cells_len {
+            Some(len) if bytes.len() >= len => {},
+            _ => return Err(FrozenError::TooShort),
+        }
+        Ok(FrozenMap {
+            ptr: bytes.as_ptr().add(cells_offset::<K, V>()) as *mut Cell<K, V>,
+            capacity,
+            size: header.size as usize,
+            hasher: H::from_seed(header.seed),
+            marker: PhantomData
+        })
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<&'a V>
+        where Q: ?Sized + Hash + Equivalent<K>
+    {
+        let hash = self.hasher.hash_one(key) as usize; // NOTE: Possible panic
+        unsafe {
+            probe_cells(self.ptr, self.capacity, hash, |k| key.equivalent(k)) // NOTE: Possible panic
+                .ok()
+                .map(|(_, pos)| &(*cell_data(self.ptr, self.capacity, pos).1).value)
+        }
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+        where Q: ?Sized + Hash + Equivalent<K>
+    {
+        self.get(key).is_some()
+    }
+
+    pub fn iter(&self) -> FrozenIter<'a, K, V> {
+        FrozenIter {
+            ptr: self.ptr,
+            capacity: self.capacity,
+            cell: 0,
+            slot: 0,
+            marker: PhantomData
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    pub fn hasher(&self) -> &H {
+        &self.hasher
+    }
+}
+
+pub struct FrozenIter<'a, K: 'a, V: 'a> {
+    ptr: *mut Cell<K, V>,
+    capacity: usize,
+    cell: usize,
+    slot: usize,
+    marker: PhantomData<&'a [Cell<K, V>]>
+}
+
+impl<'a, K: 'a, V: 'a> Iterator for FrozenIter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        unsafe {
+            while self.cell < self.capacity {
+                let cur_cell = self.ptr.add(self.cell);
+                if let Some(cur_slot) = (*cur_cell).meta.next_full(&mut self.cell, &mut self.slot) {
+                    let entry = (ptr::addr_of!((*cur_cell).data) as *const Bucket<K, V>).add(cur_slot);
+                    return Some((&(*entry).key, &(*entry).value));
+                }
+            }
+            None
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn frozen_map_works() {
+    use std::vec::Vec;
+
+    #[derive(Clone)]
+    struct Seeded(u64);
+
+    impl BuildHasher for Seeded {
+        type Hasher = ::fnv::FnvHasher;
+
+        fn build_hasher(&self) -> ::fnv::FnvHasher {
+            ::fnv::FnvHasher::with_key(self.0)
+        }
+    }
+
+    impl FrozenHasher for Seeded {
+        const ID: u64 = 1;
+
+        fn seed(&self) -> u64 {
+            self.0
+        }
+
+        fn from_seed(seed: u64) -> Self {
+            Seeded(seed)
+        }
+    }
+
+    // Bytes of a file are only guaranteed the alignment of the memory they are copied to.
+    fn aligned(bytes: &[u8]) -> Vec<u64> {
+        let mut words = vec![0u64; bytes.len().div_ceil(8)];
+        unsafe { ptr::copy_nonoverlapping(bytes.as_ptr(), words.as_mut_ptr() as *mut u8, bytes.len()) };
+        words
+    }
+
+    let max = 10000u64;
+    for mut map in ::HashMapBuilder::new(Seeded(0xcbf29ce484222325 ^ 7)).build_both() {
+        // Leaves garbage in the memory the cells are likely to get.
+        drop(vec![0xabu8; 1 << 20]);
+        for n in 0..max {
+            map.insert(n, n as u32 * 2);
+        }
+        let mut bytes = Vec::new();
+        map.freeze(&mut bytes).unwrap();
+        // Buckets of a u64 and a u32 end in four bytes of padding, which are written as zeroes.
+        let bucket_size = mem::size_of::<::Bucket<u64, u32>>();
+        for cell in bytes[cells_offset::<u64, u32>()..].chunks(mem::size_of::<Cell<u64, u32>>()) {
+            assert!(cell[BLOCK_SIZE..].chunks(bucket_size).all(|bucket| bucket[12..] == [0; 4]));
+        }
+        let words = aligned(&bytes);
+        let frozen_bytes = unsafe { slice::from_raw_parts(words.as_ptr() as *const u8, bytes.len()) };
+        let frozen = unsafe { FrozenMap::<u64, u32, Seeded>::open(frozen_bytes).unwrap() };
+        assert_eq!(max as usize, frozen.len());
+        assert!((0..max).all(|n| frozen.get(&n) == Some(&(n as u32 * 2))));
+        assert!(frozen.get(&max).is_none() && !frozen.contains_key(&(max + 1)));
+        assert_eq!(max as usize, frozen.iter().count());
+        assert_eq!((0..max).sum::<u64>(), frozen.iter().map(|(k, _)| *k).sum::<u64>());
+        assert_eq!(map.hasher().0, frozen.hasher().0);
+
+        unsafe {
+            assert_eq!(Some(FrozenError::Misaligned), FrozenMap::<u64, u32, Seeded>::open(&frozen_bytes[1..]).err());
+            assert_eq!(Some(FrozenError::TooShort), FrozenMap::<u64, u32, Seeded>::open(&frozen_bytes[..bytes.len() - 1]).err());
+            assert_eq!(Some(FrozenError::LayoutMismatch), FrozenMap::<u64, u64, Seeded>::open(frozen_bytes).err());
+            let mut words = words.clone();
+            words[2] = 2;
+            let tampered = slice::from_raw_parts(words.as_ptr() as *const u8, bytes.len());
+            assert_eq!(Some(FrozenError::HasherMismatch), FrozenMap::<u64, u32, Seeded>::open(tampered).err());
+            words[1] = 2;
+            let tampered = slice::from_raw_parts(words.as_ptr() as *const u8, bytes.len());
+            assert_eq!(Some(FrozenError::UnsupportedVersion(2)), FrozenMap::<u64, u32, Seeded>::open(tampered).err());
+            words[0] = 0;
+            let tampered = slice::from_raw_parts(words.as_ptr() as *const u8, bytes.len());
+            assert_eq!(Some(FrozenError::BadMagic), FrozenMap::<u64, u32, Seeded>::open(tampered).err());
+        }
+    }
+
+    let mut empty = HashMap::with_hasher(Seeded(1));
+    empty.insert(1u8, 1u8);
+    empty.remove(&1);
+    let mut bytes = Vec::new();
+    empty.freeze(&mut bytes).unwrap();
+    let words = aligned(&bytes);
+    let frozen = unsafe { FrozenMap::<u8, u8, Seeded>::open(slice::from_raw_parts(words.as_ptr() as *const u8, bytes.len())).unwrap() };
+    assert!(frozen.is_empty() && frozen.get(&1).is_none() && frozen.iter().next().is_none());
+}
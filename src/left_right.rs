@@ -0,0 +1,359 @@
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cell::{Cell, UnsafeCell};
+use core::hash::{BuildHasher, Hash};
+use core::marker::PhantomData;
+use core::ops::Deref;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use std::sync::{Mutex, PoisonError};
+use std::thread;
+
+use {Equivalent, HashMap, RawEntryMut};
+
+// Readers look at one copy of the map while the writer changes the other one. Refreshing swaps
+// the copies, waits until no reader is left on the old one and then replays the logged changes
+// on it, so that the copies are equal again.
+//
+// Every read handle has an epoch counter that is odd while it is reading. Once the copies are
+// swapped, the writer only has to wait for the handles that were reading at that moment.
+struct Inner<K, V, H> {
+    maps: [UnsafeCell<HashMap<K, V, H>>; 2],
+    // Index of the copy readers use, the writer owns the other one
+    active: AtomicUsize,
+    epochs: Mutex<Vec<Arc<Epoch>>>
+}
+
+// Kept on its own cache line, so that readers bumping their epochs do not slow each other down.
+#[repr(align(128))]
+struct Epoch(AtomicUsize);
+
+unsafe impl<K: Send + Sync, V: Send + Sync, H: Send + Sync> Send for Inner<K, V, H> {}
+
+unsafe impl<K: Send + Sync, V: Send + Sync, H: Send + Sync> Sync for Inner<K, V, H> {}
+
+enum Operation<K, V> {
+    Insert(K, V),
+    Remove(K),
+    Clear
+}
+
+// Makes a writer and a reader out of the map. Values are cloned once when inserted, so that
+// both copies get their own.
+pub fn new<K, V, H>(map: HashMap<K, V, H>) -> (WriteHandle<K, V, H>, ReadHandle<K, V, H>)
+    where K: Hash + PartialEq + Clone,
+          V: Clone,
+          H: BuildHasher + Clone
+{
+    let inner = Arc::new(Inner {
+        maps: [UnsafeCell::new(map.clone()), UnsafeCell::new(map)],
+        active: AtomicUsize::new(0),
+        epochs: Mutex::new(Vec::new())
+    });
+    let reader = ReadHandle::new(inner.clone());
+    let writer = WriteHandle {
+        inner,
+        log: Vec::new(),
+        behind: VecDeque::new()
+    };
+    (writer, reader)
+}
+
+pub struct WriteHandle<K, V, H> {
+    inner: Arc<Inner<K, V, H>>,
+    // Changes made to the pending copy and not yet published
+    log: Vec<Operation<K, V>>,
+    // Published changes the pending copy has not caught up with, left over if replaying panicked
+    behind: VecDeque<Operation<K, V>>
+}
+
+impl<K, V, H> WriteHandle<K, V, H>
+    where K: Hash + PartialEq + Clone,
+          V: Clone,
+          H: BuildHasher
+{
+    // The copy only the writer can see, with the changes not yet published.
+    pub fn pending(&self) -> &HashMap<K, V, H> {
+        unsafe { &*self.inner.maps[1 - self.inner.active.load(Ordering::Relaxed)].get() }
+    }
+
+    fn pending_mut(&mut self) -> &mut HashMap<K, V, H> {
+        unsafe { &mut *self.inner.maps[1 - self.inner.active.load(Ordering::Relaxed)].get() }
+    }
+
+    pub fn insert(&mut self, key: K, value: V) {
+        self.catch_up();
+        self.pending_mut().insert(key.clone(), value.clone()); // NOTE: Possible panic
+        self.log.push(Operation::Insert(key, value));
+    }
+
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+        where Q: ?Sized + Hash + Equivalent<K>
+    {
+        self.catch_up();
+        let (key, value) = self.pending_mut().remove(key)?; // NOTE: Possible panic
+        self.log.push(Operation::Remove(key));
+        Some(value)
+    }
+
+    pub fn clear(&mut self) {
+        self.catch_up();
+        self.pending_mut().clear();
+        self.log.push(Operation::Clear);
+    }
+
+    // Publishes the changes made since the last refresh. Blocks until readers that were in the
+    // middle of reading have let go of the copy they were looking at.
+    pub fn refresh(&mut self) {
+        self.catch_up();
+        let inner = &*self.inner;
+        let published = 1 - inner.active.load(Ordering::Relaxed);
+        inner.active.store(published, Ordering::SeqCst);
+        // The list is copied, so that handles can be made and dropped while waiting.
+        let epochs = inner.epochs.lock().unwrap_or_else(PoisonError::into_inner).clone();
+        for epoch in epochs {
+            let started = epoch.0.load(Ordering::SeqCst);
+            if started % 2 == 1 {
+                while epoch.0.load(Ordering::Acquire) == started {
+                    thread::yield_now();
+                }
+            }
+        }
+        self.behind.extend(self.log.drain(..));
+        self.catch_up();
+    }
+
+    // Replays the published changes on the pending copy. Changes are taken off one at a time,
+    // so that should one of them panic, the rest are replayed before the next write.
+    fn catch_up(&mut self) {
+        while let Some(operation) = self.behind.pop_front() {
+            let map = self.pending_mut();
+            match operation {
+                Operation::Insert(key, value) => {
+                    map.insert(key, value); // NOTE: Possible panic
+                },
+                Operation::Remove(key) => {
+                    let hash = map.hash(&key); // NOTE: Possible panic
+                    if let RawEntryMut::Occupied(entry) = map.raw_entry_mut().from_hash(hash, |k| *k == key) { // NOTE: Possible panic
+                        entry.remove();
+                    }
+                },
+                Operation::Clear => map.clear(),
+            }
+        }
+    }
+
+    pub fn reader(&self) -> ReadHandle<K, V, H> {
+        ReadHandle::new(self.inner.clone())
+    }
+}
+
+// Every thread needs its own handle, which is what cloning is for.
+pub struct ReadHandle<K, V, H> {
+    inner: Arc<Inner<K, V, H>>,
+    epoch: Arc<Epoch>,
+    // Number of guards of this handle alive, only the outermost one touches the epoch
+    depth: Cell<usize>,
+    marker: PhantomData<*const ()>
+}
+
+unsafe impl<K: Send + Sync, V: Send + Sync, H: Send + Sync> Send for ReadHandle<K, V, H> {}
+
+impl<K, V, H> ReadHandle<K, V, H> {
+    fn new(inner: Arc<Inner<K, V, H>>) -> Self {
+        let epoch = Arc::new(Epoch(AtomicUsize::new(0)));
+        inner.epochs.lock().unwrap_or_else(PoisonError::into_inner).push(epoch.clone());
+        ReadHandle {
+            inner,
+            epoch,
+            depth: Cell::new(0),
+            marker: PhantomData
+        }
+    }
+
+    // Reading never waits for the writer.
+    pub fn read(&self) -> ReadGuard<'_, HashMap<K, V, H>> {
+        if self.depth.get() == 0 {
+            self.epoch.0.fetch_add(1, Ordering::SeqCst);
+        }
+        self.depth.set(self.depth.get() + 1);
+        let active = self.inner.active.load(Ordering::SeqCst);
+        ReadGuard {
+            epoch: &self.epoch.0,
+            depth: &self.depth,
+            value: self.inner.maps[active].get()
+        }
+    }
+}
+
+impl<K, V, H> ReadHandle<K, V, H>
+    where K: Hash + PartialEq,
+          H: BuildHasher
+{
+    pub fn get<Q>(&self, key: &Q) -> Option<ReadGuard<'_, V>>
+        where Q: ?Sized + Hash + Equivalent<K>
+    {
+        let map = self.read();
+        let value = map.get(key)? as *const V; // NOTE: Possible panic
+        Some(map.with_value(value))
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+        where Q: ?Sized + Hash + Equivalent<K>
+    {
+        self.read().get(key).is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.read().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.read().is_empty()
+    }
+}
+
+impl<K, V, H> Clone for ReadHandle<K, V, H> {
+    fn clone(&self) -> Self {
+        ReadHandle::new(self.inner.clone())
+    }
+}
+
+impl<K, V, H> Drop for ReadHandle<K, V, H> {
+    fn drop(&mut self) {
+        let mut epochs = self.inner.epochs.lock().unwrap_or_else(PoisonError::into_inner);
+        epochs.retain(|epoch| !Arc::ptr_eq(epoch, &self.epoch));
+    }
+}
+
+pub struct ReadGuard<'a, T: 'a> {
+    epoch: &'a AtomicUsize,
+    depth: &'a Cell<usize>,
+    value: *const T
+}
+
+impl<'a, T: 'a> ReadGuard<'a, T> {
+    fn with_value<U>(self, value: *const U) -> ReadGuard<'a, U> {
+        self.depth.set(self.depth.get() + 1);
+        ReadGuard {
+            epoch: self.epoch,
+            depth: self.depth,
+            value
+        }
+    }
+}
+
+impl<'a, T: 'a> Deref for ReadGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.value }
+    }
+}
+
+impl<'a, T: 'a> Drop for ReadGuard<'a, T> {
+    fn drop(&mut self) {
+        self.depth.set(self.depth.get() - 1);
+        if self.depth.get() == 0 {
+            self.epoch.fetch_add(1, Ordering::Release);
+        }
+    }
+}
+
+#[test]
+fn left_right_works() {
+    let (mut writer, reader) = new(HashMap::with_hasher(::fnv::FnvBuildHasher::default()));
+    writer.insert(1, 1);
+    writer.insert(2, 2);
+    assert!(reader.is_empty());
+    assert_eq!(2, writer.pending().len());
+    writer.refresh();
+    assert_eq!(2, reader.len());
+    assert_eq!(1, *reader.get(&1).unwrap());
+
+    let guard = reader.get(&2).unwrap();
+    let other = reader.clone();
+    assert_eq!(2, *other.get(&2).unwrap());
+    writer.remove(&2);
+    writer.insert(3, 3);
+    assert!(reader.contains_key(&2) && !reader.contains_key(&3));
+    drop(guard);
+    writer.refresh();
+    assert!(!reader.contains_key(&2) && other.contains_key(&3));
+
+    // The copy readers left has to catch up before it is written to again.
+    writer.insert(4, 4);
+    writer.refresh();
+    writer.clear();
+    writer.insert(5, 5);
+    assert_eq!(3, reader.len());
+    writer.refresh();
+    assert_eq!(1, reader.len());
+    writer.refresh();
+    let (first, second) = unsafe { (&*writer.inner.maps[0].get(), &*writer.inner.maps[1].get()) };
+    assert_eq!(first, second);
+}
+
+#[test]
+fn panicking_refresh_is_safe() {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use {Bomb, Fuse};
+
+    let fuse = Fuse::default();
+    let (mut writer, reader) = new(HashMap::with_hasher(::fnv::FnvBuildHasher::default()));
+    for n in 0..10 {
+        writer.insert(Bomb(n, &fuse), n);
+    }
+    fuse.hashes.set(Some(3));
+    assert!(catch_unwind(AssertUnwindSafe(|| writer.refresh())).is_err());
+    assert_eq!(10, reader.len());
+
+    // Only the insertion that panicked is missing from the other copy.
+    writer.insert(Bomb(10, &fuse), 10);
+    writer.refresh();
+    assert_eq!(10, reader.len());
+    assert_eq!(11, writer.pending().len());
+    let map = reader.read();
+    assert_eq!(1, (0..11).filter(|&n| map.get(&Bomb(n, &fuse)).is_none()).count());
+}
+
+#[test]
+fn concurrent_left_right_works() {
+    use std::sync::atomic::AtomicBool;
+
+    let max = 2000u64;
+    let (mut writer, reader) = new(HashMap::with_hasher(::fnv::FnvBuildHasher::default()));
+    let done = Arc::new(AtomicBool::new(false));
+    let handles = (0..4)
+        .map(|_| {
+            let reader = reader.clone();
+            let done = done.clone();
+            thread::spawn(move || {
+                let mut seen = 0;
+                while !done.load(Ordering::SeqCst) {
+                    let map = reader.read();
+                    // Keys are published in order, so every key below the count is there.
+                    let len = map.len() as u64;
+                    assert!(len >= seen);
+                    for n in (0..len).step_by(97) {
+                        assert_eq!(Some(&(2 * n)), map.get(&n));
+                    }
+                    seen = len;
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for n in 0..max {
+        writer.insert(n, 2 * n);
+        if n % 10 == 0 {
+            writer.refresh();
+        }
+    }
+    writer.refresh();
+    done.store(true, Ordering::SeqCst);
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(max as usize, reader.len());
+}
@@ -1,15 +1,83 @@
+#![no_std]
+
+#[cfg(any(feature = "std", test))]
+#[cfg_attr(test, macro_use)]
+extern crate std;
+extern crate alloc;
+#[cfg(feature = "rayon")]
+extern crate rayon;
+#[cfg(feature = "serde")]
+extern crate serde;
 #[cfg(test)]
 extern crate fnv;
 #[cfg(test)]
 extern crate rand;
+#[cfg(all(test, feature = "serde"))]
+extern crate serde_json;
+#[cfg(all(test, feature = "serde"))]
+extern crate bincode;
+
+use alloc::alloc::handle_alloc_error;
+use core::alloc::Layout;
+use core::cmp;
+use core::mem::{self, ManuallyDrop};
+use core::ptr::{self, NonNull};
+use core::hash::{BuildHasher, Hash, Hasher};
+use core::iter::FromIterator;
+use core::ops::Index;
+use core::fmt;
+
+use builder::Policy;
+use simd::{empty_mask, BitMask};
+
+#[cfg(test)]
+use std::string::{String, ToString};
+#[cfg(test)]
+use std::vec::Vec;
 
-use std::mem::{self, ManuallyDrop};
-use std::ptr;
-use std::hash::{BuildHasher, Hash, Hasher};
-use std::fmt;
+pub use allocator::{AllocError, Allocator, Global};
+pub use atomic::AtomicInsertOnlyMap;
+pub use batch::{ContainsBatch, GetBatch};
+pub use builder::HashMapBuilder;
+pub use entry::{Entry, OccupiedEntry, VacantEntry};
+pub use equivalent::{Composite, Equivalent};
+pub use frozen::{FrozenError, FrozenHasher, FrozenIter, FrozenMap};
+pub use raw_entry::{RawEntryBuilder, RawEntryBuilderMut, RawEntryMut, RawOccupiedEntryMut, RawVacantEntryMut};
+pub use set::HashSet;
+#[cfg(feature = "std")]
+pub use sharded::ShardedMap;
+pub use stats::Stats;
+#[cfg(feature = "debug-invariants")]
+pub use validate::InvariantError;
+#[cfg(feature = "serde")]
+pub use serialize::Sorted;
+
+mod allocator;
+mod atomic;
+mod batch;
+mod builder;
+mod entry;
+mod equivalent;
+mod frozen;
+#[cfg(feature = "std")]
+pub mod left_right;
+mod raw_entry;
+mod simd;
+#[cfg(feature = "rayon")]
+pub mod parallel;
+pub mod set;
+#[cfg(feature = "std")]
+pub mod sharded;
+mod stats;
+#[cfg(feature = "serde")]
+mod serialize;
+#[cfg(feature = "debug-invariants")]
+mod validate;
 
 const BLOCK_SIZE: usize = 16;
 
+const MAX_LOAD_FACTOR: f32 = 0.872;
+
 const JUMP_DISTANCES: [usize; 126] = [
     0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
 
@@ -31,6 +99,7 @@ const JUMP_DISTANCES: [usize; 126] = [
     5209859150892887590,
 ];
 
+#[repr(transparent)]
 struct Metadata(u8);
 impl Metadata {
     fn is_empty(&self) -> bool {
@@ -83,24 +152,57 @@ impl Default for Metadata {
 }
 
 #[derive(Default)]
+#[repr(transparent)]
 struct Metadatum([Metadata; BLOCK_SIZE]);
 
-struct Entry<K, V> {
+impl Metadatum {
+    fn empty_mask(&self) -> u16 {
+        empty_mask(unsafe { &*(self as *const Metadatum as *const [u8; BLOCK_SIZE]) })
+    }
+
+    fn full_mask(&self) -> u16 {
+        !self.empty_mask()
+    }
+
+    fn clear(&mut self) {
+        *self = Metadatum::default();
+    }
+
+    // Finds the first full slot at or after `slot` and moves the position past it,
+    // or moves the position to the start of the next cell if there is none.
+    fn next_full(&self, cell: &mut usize, slot: &mut usize) -> Option<usize> {
+        let found = BitMask(self.full_mask() & (!0 << *slot)).next();
+        match found {
+            Some(cur_slot) if cur_slot + 1 < BLOCK_SIZE => *slot = cur_slot + 1,
+            _ => {
+                *slot = 0;
+                *cell += 1;
+            },
+        }
+        found
+    }
+}
+
+#[repr(C)]
+struct Bucket<K, V> {
     key: K,
     value: V
 }
 
-impl<K, V> Entry<K, V> {
+impl<K, V> Bucket<K, V> {
     fn new(key: K, value: V) -> Self {
-        Entry {
+        Bucket {
             key,
             value
         }
     }
 }
 
-struct Datum<K, V>([Entry<K, V>; BLOCK_SIZE]);
+#[repr(C)]
+struct Datum<K, V>([Bucket<K, V>; BLOCK_SIZE]);
 
+// Layouts are fixed, as `HashMap::freeze` writes cells as they are in memory.
+#[repr(C)]
 struct Cell<K, V> {
     meta: Metadatum,
     data: ManuallyDrop<Datum<K, V>>
@@ -109,45 +211,143 @@ struct Cell<K, V> {
 impl<K, V> Drop for Cell<K, V> {
     fn drop(&mut self) {
         unsafe {
-            for slot in 0..BLOCK_SIZE {
-                if !self.meta.0[slot].is_empty() {
-                    let datum_ptr = self.data.0.as_mut().as_mut_ptr();
-                    datum_ptr.offset(slot as isize).drop_in_place();
-                }
+            for slot in BitMask(self.meta.full_mask()) {
+                let datum_ptr = self.data.0.as_mut().as_mut_ptr();
+                datum_ptr.add(slot).drop_in_place();
             }
         }
     }
 }
 
-pub struct HashMap<K, V, H> {
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TryReserveError {
+    CapacityOverflow,
+    AllocError {
+        layout: Layout
+    },
+}
+
+impl fmt::Display for TryReserveError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TryReserveError::CapacityOverflow => fmt.write_str("capacity overflow"),
+            TryReserveError::AllocError { layout } => write!(fmt, "failed to allocate {} bytes", layout.size()),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for TryReserveError {}
+
+// Hands the entry that could not be inserted back to the caller.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TryInsertError<K, V> {
+    pub error: TryReserveError,
+    pub key: K,
+    pub value: V
+}
+
+impl<K, V> fmt::Display for TryInsertError<K, V> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        self.error.fmt(fmt)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<K: fmt::Debug, V: fmt::Debug> std::error::Error for TryInsertError<K, V> {}
+
+// Inserted bucket, or the bucket that could not be inserted
+type InsertResult<K, V> = Result<*mut Bucket<K, V>, (TryReserveError, Bucket<K, V>)>;
+
+#[derive(Clone, Copy)]
+enum Vacancy {
+    // Home slot is empty
+    Empty,
+    // Home slot is used by other chain and has to be freed
+    Storage,
+    // Chain starting from home slot ends at given position
+    Tail(usize),
+}
+
+// Cells of the previous table while an incremental resize moves their buckets over
+struct OldCells<K, V> {
+    ptr: *mut Cell<K, V>,
+    capacity: usize,
+    size: usize,
+    // Chains starting in cells before this one have been moved
+    cell: usize
+}
+
+pub struct HashMap<K, V, H, A: Allocator = Global> {
     ptr: *mut Cell<K, V>,
     size: usize,
     capacity: usize,
-    hasher: H
+    old: Option<OldCells<K, V>>,
+    policy: Policy,
+    hasher: H,
+    alloc: A
 }
 
-impl<K, V, H> Drop for HashMap<K, V, H> {
+unsafe impl<K: Send, V: Send, H: Send, A: Allocator + Send> Send for HashMap<K, V, H, A> {}
+
+unsafe impl<K: Sync, V: Sync, H: Sync, A: Allocator + Sync> Sync for HashMap<K, V, H, A> {}
+
+impl<K, V, H, A: Allocator> Drop for HashMap<K, V, H, A> {
     fn drop(&mut self) {
         unsafe {
-            drop(Vec::from_raw_parts(self.ptr, 0, self.capacity));
+            ptr::drop_in_place(ptr::slice_from_raw_parts_mut(self.ptr, self.capacity));
+            deallocate(self.ptr, self.capacity, self.policy.store_hashes, &self.alloc);
+            if let Some(old) = self.old.take() {
+                ptr::drop_in_place(ptr::slice_from_raw_parts_mut(old.ptr, old.capacity));
+                deallocate(old.ptr, old.capacity, self.policy.store_hashes, &self.alloc);
+            }
+        }
+    }
+}
+
+impl<K, V, H, A: Allocator> HashMap<K, V, H, A> {
+    // Number of cells visited when iterating, including the ones still being migrated
+    fn cell_count(&self) -> usize {
+        self.capacity + self.old.as_ref().map_or(0, |old| old.capacity)
+    }
+
+    // Indices past the current cells refer to the cells still being migrated.
+    fn cell_ptr(&self, cell: usize) -> *mut Cell<K, V> {
+        unsafe {
+            match self.old {
+                Some(ref old) if cell >= self.capacity => old.ptr.add(cell - self.capacity),
+                _ => self.ptr.add(cell),
+            }
         }
     }
 }
 
-impl<'a, K: 'a, V: 'a, H: 'a> IntoIterator for &'a HashMap<K, V, H>
+impl<K, V, H, A: Allocator> IntoIterator for HashMap<K, V, H, A>
+    where K: Hash + PartialEq,
+          H: BuildHasher
+{
+    type IntoIter = IntoIter<K, V, H, A>;
+    type Item = (K, V);
+    fn into_iter(mut self) -> Self::IntoIter {
+        self.finish_migration();
+        IntoIter(self, 0, 0)
+    }
+}
+
+impl<'a, K: 'a, V: 'a, H: 'a, A: Allocator + 'a> IntoIterator for &'a HashMap<K, V, H, A>
     where K: Hash + PartialEq,
           H: BuildHasher
 {
-    type IntoIter = Iter<'a, K, V, H>;
+    type IntoIter = Iter<'a, K, V, H, A>;
     type Item = (&'a K, &'a V);
     fn into_iter(self) -> Self::IntoIter {
         Iter(self, 0, 0)
     }
 }
 
-pub struct Iter<'a, K: 'a, V: 'a, H: 'a>(&'a HashMap<K, V, H>, usize, usize);
+pub struct Iter<'a, K: 'a, V: 'a, H: 'a, A: Allocator + 'a = Global>(&'a HashMap<K, V, H, A>, usize, usize);
 
-impl<'a, K: 'a, V: 'a, H: 'a> Iterator for Iter<'a, K, V, H>
+impl<'a, K: 'a, V: 'a, H: 'a, A: Allocator + 'a> Iterator for Iter<'a, K, V, H, A>
     where K: Hash + PartialEq,
           H: BuildHasher
 {
@@ -156,17 +356,10 @@ impl<'a, K: 'a, V: 'a, H: 'a> Iterator for Iter<'a, K, V, H>
     fn next(&mut self) -> Option<Self::Item> {
         unsafe {
             let (cell, slot) = (&mut self.1, &mut self.2);
-            while *cell < self.0.capacity {
-                let cur_cell = self.0.ptr.offset(*cell as isize);
-                let cur_slot = *slot;
-                *slot += 1;
-                if *slot >= BLOCK_SIZE {
-                    *slot = 0;
-                    *cell += 1;
-                }
-                if !(*cur_cell).meta.0[cur_slot].is_empty() {
-                    let datum_ptr = (*cur_cell).data.0.as_ref().as_ptr();
-                    let entry = datum_ptr.offset(cur_slot as isize);
+            while *cell < self.0.cell_count() {
+                let cur_cell = self.0.cell_ptr(*cell);
+                if let Some(cur_slot) = (*cur_cell).meta.next_full(cell, slot) {
+                    let entry = (ptr::addr_of!((*cur_cell).data) as *const Bucket<K, V>).add(cur_slot);
                     return Some((&(*entry).key, &(*entry).value));
                 }
             }
@@ -175,9 +368,9 @@ impl<'a, K: 'a, V: 'a, H: 'a> Iterator for Iter<'a, K, V, H>
     }
 }
 
-pub struct IterMut<'a, K: 'a, V: 'a, H: 'a>(&'a mut HashMap<K, V, H>, usize, usize);
+pub struct IterMut<'a, K: 'a, V: 'a, H: 'a, A: Allocator + 'a = Global>(&'a mut HashMap<K, V, H, A>, usize, usize);
 
-impl<'a, K: 'a, V: 'a, H: 'a> Iterator for IterMut<'a, K, V, H>
+impl<'a, K: 'a, V: 'a, H: 'a, A: Allocator + 'a> Iterator for IterMut<'a, K, V, H, A>
     where K: Hash + PartialEq,
           H: BuildHasher
 {
@@ -186,17 +379,10 @@ impl<'a, K: 'a, V: 'a, H: 'a> Iterator for IterMut<'a, K, V, H>
     fn next(&mut self) -> Option<Self::Item> {
         unsafe {
             let (cell, slot) = (&mut self.1, &mut self.2);
-            while *cell < self.0.capacity {
-                let cur_cell = self.0.ptr.offset(*cell as isize);
-                let cur_slot = *slot;
-                *slot += 1;
-                if *slot >= BLOCK_SIZE {
-                    *slot = 0;
-                    *cell += 1;
-                }
-                if !(*cur_cell).meta.0[cur_slot].is_empty() {
-                    let datum_ptr = (*cur_cell).data.0.as_mut().as_mut_ptr();
-                    let entry = datum_ptr.offset(cur_slot as isize);
+            while *cell < self.0.cell_count() {
+                let cur_cell = self.0.cell_ptr(*cell);
+                if let Some(cur_slot) = (*cur_cell).meta.next_full(cell, slot) {
+                    let entry = (ptr::addr_of_mut!((*cur_cell).data) as *mut Bucket<K, V>).add(cur_slot);
                     return Some((&mut (*entry).key, &mut (*entry).value));
                 }
             }
@@ -205,355 +391,1222 @@ impl<'a, K: 'a, V: 'a, H: 'a> Iterator for IterMut<'a, K, V, H>
     }
 }
 
-impl<K, V, H> Default for HashMap<K, V, H>
-    where K: Hash + PartialEq,
-          H: BuildHasher + Default
-{
-    fn default() -> Self {
-        Self::with_hasher(H::default())
-    }
-}
+pub struct IntoIter<K, V, H, A: Allocator = Global>(HashMap<K, V, H, A>, usize, usize);
 
-impl<K, V, H> HashMap<K, V, H>
+impl<K, V, H, A: Allocator> Iterator for IntoIter<K, V, H, A>
     where K: Hash + PartialEq,
           H: BuildHasher
 {
-    pub fn with_hasher(hasher: H) -> Self {
-        HashMap {
-            ptr: allocate(1),
-            size: 0,
-            capacity: 1,
-            hasher
-        }
-    }
+    type Item = (K, V);
 
-    pub fn with_capacity(capacity: usize, hasher: H) -> Self {
-        let capacity = ((capacity as f32 / BLOCK_SIZE as f32).ceil() as usize).next_power_of_two();
-        HashMap {
-            ptr: allocate(capacity),
-            size: 0,
-            capacity,
-            hasher
+    fn next(&mut self) -> Option<Self::Item> {
+        unsafe {
+            take_next(self.0.ptr, self.0.capacity, &mut self.1, &mut self.2).map(|bucket| {
+                self.0.size -= 1;
+                (bucket.key, bucket.value)
+            })
         }
     }
 
-    pub fn insert(&mut self, key: K, value: V) -> Option<(K, V)> {
-        if self.size as f32 / (BLOCK_SIZE * self.capacity) as f32 > 0.872 {
-            self.reallocate();
-        }
-        let mut hash = self.hash(&key) as usize; // NOTE: Possible panic
-        unsafe {
-            let mut cur_meta = ptr::null_mut();
-            let mut data_ptr = ptr::null_mut();
-            self.mut_data(hash, &mut cur_meta, &mut data_ptr);
-
-            if (*cur_meta).is_empty() {
-                (*cur_meta).set_last(false);
-                ptr::write(data_ptr, Entry::new(key, value));
-                self.size += 1;
-                return None;
-            } else if (*cur_meta).is_storage() {
-                let (mut prev_meta, mut relocate_meta) = (ptr::null_mut(), ptr::null_mut());
-
-                let prev_hash = self.find_previous(hash, data_ptr);
-                self.mut_data(prev_hash, &mut prev_meta, &mut ptr::null_mut());
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.0.size, Some(self.0.size))
+    }
+}
 
-                let mut first_jump = (*prev_meta).jump_length();
-                let mut to_be_moved = ptr::read(data_ptr);
-                let mut to_be_moved_place = hash;
-                let mut jump_to_next_to_be_moved = (*cur_meta).jump_length();
+pub struct Drain<'a, K: 'a, V: 'a, H: 'a, A: Allocator + 'a = Global> {
+    map: &'a mut HashMap<K, V, H, A>,
+    ptr: *mut Cell<K, V>,
+    capacity: usize,
+    remaining: usize,
+    cell: usize,
+    slot: usize
+}
 
-                let mut cur_hash = prev_hash;
+impl<'a, K: 'a, V: 'a, H: 'a, A: Allocator + 'a> Iterator for Drain<'a, K, V, H, A> {
+    type Item = (K, V);
 
-                loop {
-                    if let Some((relocate_data_ptr, jumps)) = self.find_empty(cur_hash, first_jump, &mut relocate_meta) {
-                        first_jump = 1;
-                        (*relocate_meta).set_last(true);
-                        ptr::write(relocate_data_ptr, to_be_moved);
-                        (*prev_meta).set_jump(jumps);
-                        mem::swap(&mut prev_meta, &mut relocate_meta);
+    fn next(&mut self) -> Option<Self::Item> {
+        unsafe {
+            take_next(self.ptr, self.capacity, &mut self.cell, &mut self.slot).map(|bucket| {
+                self.remaining -= 1;
+                (bucket.key, bucket.value)
+            })
+        }
+    }
 
-                        (*cur_meta).set_empty();
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
 
-                        if jump_to_next_to_be_moved == 0 {
-                            break;
-                        }
+impl<'a, K: 'a, V: 'a, H: 'a, A: Allocator + 'a> Drain<'a, K, V, H, A> {
+    // Every slot has to be empty, so that the drained cells can be given back to the map.
+    unsafe fn restore(&mut self) {
+        let placeholder = mem::replace(&mut self.map.ptr, self.ptr);
+        let placeholder_capacity = mem::replace(&mut self.map.capacity, self.capacity);
+        deallocate(placeholder, placeholder_capacity, self.map.policy.store_hashes, &self.map.alloc);
+    }
+}
 
-                        cur_hash += JUMP_DISTANCES[jumps as usize];
-                        to_be_moved_place += JUMP_DISTANCES[jump_to_next_to_be_moved as usize];
-                        self.mut_data(to_be_moved_place, &mut cur_meta, &mut data_ptr);
-                        to_be_moved = ptr::read(data_ptr);
-                        jump_to_next_to_be_moved = (*cur_meta).jump_length();
-                    } else {
-                        self.reallocate();
-                        self.insert(to_be_moved.key, to_be_moved.value);
-                        return self.insert(key, value);
-                    }
-                }
-                self.mut_data(hash, &mut cur_meta, &mut data_ptr);
-                (*cur_meta).set_last(false);
-                ptr::write(data_ptr, Entry::new(key, value));
-                self.size += 1;
-                return None;
-            }
-            loop {
-                debug_assert!(!(*cur_meta).is_empty());
-                let data = &mut *data_ptr;
-                if data.key == key { // NOTE: Possible panic
-                    let value_ptr = (&mut data.value) as *mut _;
-                    return Some((key, ptr::replace(value_ptr, value)));
-                }
-                let jump = (*cur_meta).jump_length();
-                if jump == 0 {
-                    let prev_meta = cur_meta;
-                    let mut cur_meta = ptr::null_mut();
-                    return if let Some((data_ptr, jumps)) = self.find_empty(hash, 1, &mut cur_meta) {
-                        (*cur_meta).set_last(true);
-                        (*prev_meta).set_jump(jumps);
-                        ptr::write(data_ptr, Entry::new(key, value));
-                        self.size += 1;
-                        None
-                    } else {
-                        self.reallocate();
-                        self.insert(key, value)
-                    };
-                }
-                hash += JUMP_DISTANCES[jump as usize];
+impl<'a, K: 'a, V: 'a, H: 'a, A: Allocator + 'a> Drop for Drain<'a, K, V, H, A> {
+    fn drop(&mut self) {
+        // Keeps draining when dropping an item panics, so that the cells are still given back.
+        struct Guard<'r, 'a: 'r, K: 'a, V: 'a, H: 'a, A: Allocator + 'a>(&'r mut Drain<'a, K, V, H, A>);
 
-                self.mut_data(hash, &mut cur_meta, &mut data_ptr);
+        impl<'r, 'a: 'r, K: 'a, V: 'a, H: 'a, A: Allocator + 'a> Drop for Guard<'r, 'a, K, V, H, A> {
+            fn drop(&mut self) {
+                for _ in self.0.by_ref() {}
+                unsafe { self.0.restore() };
             }
         }
-    }
 
-    unsafe fn find_previous(&self, target_hash: usize, data_ptr: *const Entry<K, V>) -> usize {
-        let mut their_hash = self.hash(&(*data_ptr).key) as usize; // NOTE: Possible panic
-        let mut prev_hash = 0;
-        let mut before_meta = ptr::null();
-        while split_hash(their_hash, self.capacity) != split_hash(target_hash, self.capacity) {
-            prev_hash = their_hash;
-            self.get_data(their_hash, &mut before_meta, &mut ptr::null());
-            debug_assert!((*before_meta).jump_length() != 0);
-            their_hash += JUMP_DISTANCES[(*before_meta).jump_length() as usize];
+        while let Some(item) = self.next() {
+            let guard = Guard(self);
+            drop(item); // NOTE: Possible panic
+            mem::forget(guard);
         }
-        return prev_hash;
+        unsafe { self.restore() };
     }
+}
 
-    unsafe fn find_empty<'b>(&mut self, hash: usize, start: u8, meta: &'b mut *mut Metadata) -> Option<(*mut Entry<K, V>, u8)> {
-        let mut data_ptr = ptr::null_mut();
-        for jumps in (start as usize)..JUMP_DISTANCES.len() {
-            let new_hash = hash.wrapping_add(JUMP_DISTANCES[jumps]);
-
-            self.mut_data(new_hash, meta, &mut data_ptr);
+// Owns the cells a resize moves buckets out of. Should rehashing panic midway, the buckets that
+// were not moved yet are dropped along with the cells instead of being leaked.
+struct Evacuated<'a, K: 'a, V: 'a, H: 'a, A: Allocator + 'a> {
+    map: &'a mut HashMap<K, V, H, A>,
+    cells: [(*mut Cell<K, V>, usize); 2]
+}
 
-            if (**meta).is_empty() {
-                return Some((data_ptr, jumps as u8));
+impl<'a, K: 'a, V: 'a, H: 'a, A: Allocator + 'a> Drop for Evacuated<'a, K, V, H, A> {
+    fn drop(&mut self) {
+        for &(ptr, capacity) in &self.cells {
+            if !ptr.is_null() {
+                unsafe {
+                    ptr::drop_in_place(ptr::slice_from_raw_parts_mut(ptr, capacity));
+                    deallocate(ptr, capacity, self.map.policy.store_hashes, &self.map.alloc);
+                }
             }
         }
-        None
     }
+}
 
-    pub fn remove(&mut self, key: &K) -> Option<(K, V)> {
-        let mut hash = self.hash(&key) as usize; // NOTE: Possible panic
-        unsafe {
-            let mut cur_meta = ptr::null_mut();
-            let mut data_ptr = ptr::null_mut();
-            self.mut_data(hash, &mut cur_meta, &mut data_ptr);
+pub struct ExtractIf<'a, K: 'a, V: 'a, H: 'a, F, A: Allocator + 'a = Global> {
+    map: &'a mut HashMap<K, V, H, A>,
+    pred: F,
+    cell: usize,
+    slot: usize,
+    // Positions of the previous and the current bucket of the chain being visited
+    chain: Option<(usize, usize)>
+}
 
-            if (*cur_meta).is_storage() {
-                return None;
-            }
-            let mut prev_meta = cur_meta;
+impl<'a, K: 'a, V: 'a, H: 'a, F, A: Allocator + 'a> Iterator for ExtractIf<'a, K, V, H, F, A>
+    where K: Hash + PartialEq,
+          H: BuildHasher,
+          F: FnMut(&K, &mut V) -> bool
+{
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        unsafe {
             loop {
-                if &(*data_ptr).key == key { // NOTE: Possible panic
-                    let data = ptr::read(data_ptr);
-                    let mut prev_ptr;
-                    loop {
-                        let jump = (*cur_meta).jump_length();
-                        if jump == 0 {
-                            (*prev_meta).set_jump(0);
-                            (*cur_meta).set_empty();
-                            break;
+                if let Some((prev, pos)) = self.chain {
+                    let mut cur_meta = ptr::null_mut();
+                    let mut data_ptr = ptr::null_mut();
+                    self.map.mut_data(pos, &mut cur_meta, &mut data_ptr);
+                    if (self.pred)(&(*data_ptr).key, &mut (*data_ptr).value) { // NOTE: Possible panic
+                        // The rest of the chain is shifted back, so the next bucket takes this position.
+                        let bucket = self.map.remove_at(prev, pos);
+                        if (*cur_meta).is_empty() {
+                            self.chain = None;
                         }
-                        prev_ptr = data_ptr;
-                        prev_meta = cur_meta;
-                        hash += JUMP_DISTANCES[jump as usize];
-                        self.mut_data(hash, &mut cur_meta, &mut data_ptr);
-                        ptr::write(prev_ptr, ptr::read(data_ptr));
+                        return Some((bucket.key, bucket.value));
                     }
-                    return Some((data.key, data.value));
-                }
-                let jump = (*cur_meta).jump_length();
-                if jump == 0 {
-                    return None;
+                    let jump = (*cur_meta).jump_length();
+                    self.chain = if jump == 0 {
+                        None
+                    } else {
+                        Some((pos, pos.wrapping_add(JUMP_DISTANCES[jump as usize])))
+                    };
+                } else {
+                    // Visit every chain once starting from its head.
+                    let (cell, slot) = (&mut self.cell, &mut self.slot);
+                    while *cell < self.map.capacity && self.chain.is_none() {
+                        let cur_cell = &*self.map.ptr.add(*cell);
+                        let meta = &cur_cell.meta.0[*slot];
+                        if !meta.is_empty() && !meta.is_storage() {
+                            let head = *cell * BLOCK_SIZE + *slot;
+                            self.chain = Some((head, head));
+                        }
+                        *slot += 1;
+                        if *slot >= BLOCK_SIZE {
+                            *slot = 0;
+                            *cell += 1;
+                        }
+                    }
+                    self.chain?;
                 }
-                hash += JUMP_DISTANCES[jump as usize];
-                prev_meta = cur_meta;
-                self.mut_data(hash, &mut cur_meta, &mut data_ptr);
             }
         }
     }
 
-    pub fn get(&self, key: &K) -> Option<&V> {
-        let mut hash = self.hash(&key) as usize; // NOTE: Possible panic
-        unsafe {
-            let mut cur_meta = ptr::null();
-            let mut data_ptr = ptr::null();
-            self.get_data(hash, &mut cur_meta, &mut data_ptr);
-
-            if (*cur_meta).is_storage() {
-                return None;
-            }
-            loop {
-                let data = &*data_ptr;
-                if &data.key == key { // NOTE: Possible panic
-                    return Some(&data.value);
-                }
-                let jump = (*cur_meta).jump_length();
-                if jump == 0 {
-                    return None;
-                }
-                hash += JUMP_DISTANCES[jump as usize];
-                self.get_data(hash, &mut cur_meta, &mut data_ptr);
-            }
-        }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.map.size))
     }
+}
 
-    // TODO: Abstract over mutability. Needs HKT/GAT
-    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
-        let mut hash = self.hash(&key) as usize; // NOTE: Possible panic
-        unsafe {
-            let mut cur_meta = ptr::null_mut();
-            let mut data_ptr = ptr::null_mut();
-            self.mut_data(hash, &mut cur_meta, &mut data_ptr);
-
-            if (*cur_meta).is_storage() {
-                return None;
-            }
-            loop {
-                let data = &mut *data_ptr;
-                if &data.key == key { // NOTE: Possible panic
-                    return Some(&mut data.value);
-                }
-                let jump = (*cur_meta).jump_length();
-                if jump == 0 {
-                    return None;
-                }
-                hash += JUMP_DISTANCES[jump as usize];
-                self.mut_data(hash, &mut cur_meta, &mut data_ptr);
-            }
-        }
+impl<K, V, H, A> Default for HashMap<K, V, H, A>
+    where K: Hash + PartialEq,
+          H: BuildHasher + Default,
+          A: Allocator + Default
+{
+    fn default() -> Self {
+        Self::with_hasher_in(H::default(), A::default())
     }
+}
 
-    fn reallocate(&mut self) {
-        let old_capacity = self.capacity;
-        let new_capacity = 2 * self.capacity;
-        self.capacity = new_capacity;
-        self.size = 0;
-        let old_ptr = mem::replace(&mut self.ptr, allocate(new_capacity));
-
+// Buckets are cloned into the same slots, so the clone has the same capacity and chains.
+// Should cloning an entry panic, the entries cloned so far are dropped with the clone.
+impl<K, V, H, A> Clone for HashMap<K, V, H, A>
+    where K: Clone,
+          V: Clone,
+          H: Clone,
+          A: Allocator + Clone
+{
+    fn clone(&self) -> Self {
+        let alloc = self.alloc.clone();
+        let mut map = HashMap {
+            ptr: allocate(self.capacity, self.policy.store_hashes, &alloc),
+            size: 0,
+            capacity: self.capacity,
+            old: None,
+            policy: self.policy,
+            hasher: self.hasher.clone(),
+            alloc
+        };
         unsafe {
-            for cell in 0..old_capacity {
-                let cur_cell = old_ptr.offset(cell as isize);
-                for slot in 0..BLOCK_SIZE {
-                    if !&(*cur_cell).meta.0[slot].is_empty() {
-                        (*cur_cell).meta.0[slot].set_empty();
-                        let datum_ptr = (*cur_cell).data.0.as_ref().as_ptr();
-                        let data_ptr = datum_ptr.offset(slot as isize);
-                        let entry = ptr::read(data_ptr);
-                        self.insert(entry.key, entry.value);
-                    }
-                }
+            if let Some(ref old) = self.old {
+                map.old = Some(OldCells {
+                    ptr: allocate(old.capacity, self.policy.store_hashes, &map.alloc),
+                    capacity: old.capacity,
+                    size: 0,
+                    cell: old.cell
+                });
+                let cloned = map.old.as_mut().unwrap();
+                clone_cells(old.ptr, cloned.ptr, old.capacity, self.policy.store_hashes); // NOTE: Possible panic
+                cloned.size = old.size;
             }
-            drop(Vec::from_raw_parts(old_ptr, 0, old_capacity))
+            clone_cells(self.ptr, map.ptr, self.capacity, self.policy.store_hashes); // NOTE: Possible panic
         }
+        map.size = self.size;
+        map
     }
+}
 
-    fn hash(&self, key: &K) -> u64 {
-        let mut state = self.hasher.build_hasher();
-        key.hash(&mut state);
-        state.finish()
+impl<K, V, H, A: Allocator> fmt::Debug for HashMap<K, V, H, A>
+    where K: Hash + PartialEq + fmt::Debug,
+          V: fmt::Debug,
+          H: BuildHasher
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_map().entries(self.iter()).finish()
     }
+}
 
-    fn get_data(&self, hash: usize, cur_meta: &mut *const Metadata, data_ptr: &mut *const Entry<K, V>) {
-        unsafe {
-            let (cell, slot) = split_hash(hash, self.capacity);
-            let cur_cell = self.ptr.offset(cell);
-
-            let meta_ptr = (*cur_cell).meta.0.as_ref().as_ptr();
-            *cur_meta = meta_ptr.offset(slot as isize);
-            
-            let datum_ptr = (*cur_cell).data.0.as_ref().as_ptr();
-            *data_ptr = datum_ptr.offset(slot as isize);
-        }
+impl<K, V, H, A: Allocator> PartialEq for HashMap<K, V, H, A>
+    where K: Hash + PartialEq,
+          V: PartialEq,
+          H: BuildHasher
+{
+    fn eq(&self, other: &HashMap<K, V, H, A>) -> bool {
+        self.len() == other.len() && self.iter().all(|(k, v)| unsafe {
+            other.find(other.hash(k) as usize, |x| *x == *k).is_some_and(|bucket| *v == (*bucket).value)
+        })
     }
-    
-    // TODO: Abstract over mutability. Needs HKT/GAT
-    fn mut_data(&mut self, hash: usize, cur_meta: &mut *mut Metadata, data_ptr: &mut *mut Entry<K, V>) {
-        unsafe {
-            let (cell, slot) = split_hash(hash, self.capacity);
-            let cur_cell = self.ptr.offset(cell);
+}
 
-            let meta_ptr = (*cur_cell).meta.0.as_mut().as_mut_ptr();
-            *cur_meta = meta_ptr.offset(slot as isize);
+impl<K, V, H, A: Allocator> Eq for HashMap<K, V, H, A>
+    where K: Hash + Eq,
+          V: Eq,
+          H: BuildHasher
+{}
 
-            let datum_ptr = (*cur_cell).data.0.as_mut().as_mut_ptr();
-            *data_ptr = datum_ptr.offset(slot as isize);
+impl<K, V, H, A: Allocator> Extend<(K, V)> for HashMap<K, V, H, A>
+    where K: Hash + PartialEq,
+          H: BuildHasher
+{
+    // Reserves for half of the hinted items when the map is not empty, as some keys may be present.
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I) {
+        let iter = iter.into_iter();
+        let hint = iter.size_hint().0;
+        self.reserve(if self.is_empty() { hint } else { hint.div_ceil(2) });
+        for (k, v) in iter {
+            self.insert(k, v);
         }
     }
+}
 
-    fn debug(&self) {
-        unsafe {
-            let numbers = (self.capacity as f32).log(10.) as usize + 1;
-            for cell in 0..self.capacity {
-                let cur_cell = self.ptr.offset(cell as isize);
-                print!("{:w$} ", cell, w = numbers);
-                for slot in 0..BLOCK_SIZE {
-                    print!("{:?}", (*cur_cell).meta.0[slot]);
-                }
-                print!(" ");
-                if cell % 2 == 1 {
-                    println!();
-                }
-            }
-            if self.capacity % 2 == 1 {
-                println!();
-            }
-        }
+impl<'a, K, V, H, A: Allocator> Extend<(&'a K, &'a V)> for HashMap<K, V, H, A>
+    where K: Hash + PartialEq + Copy,
+          V: Copy,
+          H: BuildHasher
+{
+    fn extend<I: IntoIterator<Item = (&'a K, &'a V)>>(&mut self, iter: I) {
+        self.extend(iter.into_iter().map(|(&k, &v)| (k, v)));
     }
 }
 
-fn allocate<K, V>(capacity: usize) -> *mut Cell<K, V> {
-    // TODO: This is inefficent
-    let mut data = Vec::with_capacity(capacity);
-    for _ in 0..capacity {
-        data.push(Cell {
-            meta: Metadatum::default(),
-            data: unsafe { mem::uninitialized() },
-        });
+impl<K, V, H, A> FromIterator<(K, V)> for HashMap<K, V, H, A>
+    where K: Hash + PartialEq,
+          H: BuildHasher + Default,
+          A: Allocator + Default
+{
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let mut map = Self::default();
+        map.extend(iter);
+        map
     }
-    let ptr = data.as_mut_ptr();
-    mem::forget(data);
-    ptr
 }
 
-fn split_hash(hash: usize, capacity: usize) -> (isize, usize) {
-    (
-        ((hash / BLOCK_SIZE) & (capacity - 1)) as isize,
-        hash & (BLOCK_SIZE - 1)
-    )
+impl<K, V, H, A, const N: usize> From<[(K, V); N]> for HashMap<K, V, H, A>
+    where K: Hash + PartialEq,
+          H: BuildHasher + Default,
+          A: Allocator + Default
+{
+    fn from(entries: [(K, V); N]) -> Self {
+        let mut map = Self::with_capacity_in(N, H::default(), A::default());
+        map.extend(entries);
+        map
+    }
 }
 
-#[test]
-fn adding_one_works() {
-    let max = 10000;
-    for n in 0..max {
-        let mut map = HashMap::with_hasher(::fnv::FnvBuildHasher::default());
-        map.insert(n, n);
-        assert_eq!(Some(&n), map.get(&n));
+impl<K, V, H, A: Allocator, Q> Index<&Q> for HashMap<K, V, H, A>
+    where K: Hash + PartialEq,
+          H: BuildHasher,
+          Q: ?Sized + Hash + Equivalent<K>
+{
+    type Output = V;
+
+    fn index(&self, key: &Q) -> &V {
+        self.get(key).expect("no entry found for key")
     }
 }
 
-#[test]
+impl<K, V, H> HashMap<K, V, H>
+    where K: Hash + PartialEq,
+          H: BuildHasher
+{
+    pub fn with_hasher(hasher: H) -> Self {
+        Self::with_hasher_in(hasher, Global)
+    }
+
+    pub fn with_capacity(capacity: usize, hasher: H) -> Self {
+        Self::with_capacity_in(capacity, hasher, Global)
+    }
+
+    pub fn try_with_capacity(capacity: usize, hasher: H) -> Result<Self, TryReserveError> {
+        Self::try_with_capacity_in(capacity, hasher, Global)
+    }
+}
+
+impl<K, V, H, A> HashMap<K, V, H, A>
+    where K: Hash + PartialEq,
+          H: BuildHasher,
+          A: Allocator
+{
+    pub fn with_hasher_in(hasher: H, alloc: A) -> Self {
+        HashMap {
+            ptr: allocate(1, false, &alloc),
+            size: 0,
+            capacity: 1,
+            old: None,
+            policy: Policy::default(),
+            hasher,
+            alloc
+        }
+    }
+
+    pub fn with_capacity_in(capacity: usize, hasher: H, alloc: A) -> Self {
+        infallible(Self::try_with_capacity_in(capacity, hasher, alloc))
+    }
+
+    pub fn try_with_capacity_in(capacity: usize, hasher: H, alloc: A) -> Result<Self, TryReserveError> {
+        Self::try_with_policy_in(capacity, Policy::default(), hasher, alloc)
+    }
+
+    pub(crate) fn with_policy_in(capacity: usize, policy: Policy, hasher: H, alloc: A) -> Self {
+        infallible(Self::try_with_policy_in(capacity, policy, hasher, alloc))
+    }
+
+    pub(crate) fn try_with_policy_in(capacity: usize, policy: Policy, hasher: H, alloc: A) -> Result<Self, TryReserveError> {
+        let capacity = policy.cells_for(capacity)?;
+        Ok(HashMap {
+            ptr: try_allocate(capacity, policy.store_hashes, &alloc)?,
+            size: 0,
+            capacity,
+            old: None,
+            policy,
+            hasher,
+            alloc
+        })
+    }
+
+    pub fn allocator(&self) -> &A {
+        &self.alloc
+    }
+
+    pub fn reserve(&mut self, additional: usize) {
+        infallible(self.try_reserve(additional))
+    }
+
+    // Inserting can still grow the map when it runs out of jumps, which `try_insert` reports too.
+    pub fn try_reserve(&mut self, additional: usize) -> Result<(), TryReserveError> {
+        let items = self.len().checked_add(additional).ok_or(TryReserveError::CapacityOverflow)?;
+        let capacity = self.policy.cells_for(items)?;
+        if capacity > self.capacity {
+            // Grow at least as much as inserting would, so that repeated reserves stay amortized
+            let grown = self.policy.grown(self.capacity).unwrap_or(capacity);
+            self.try_resize(capacity.max(grown))
+        } else {
+            Ok(())
+        }
+    }
+
+    // Number of items the map can hold before the load factor forces it to grow
+    pub fn capacity(&self) -> usize {
+        self.policy.items_for(self.capacity)
+    }
+
+    pub fn shrink_to(&mut self, min_capacity: usize) {
+        let capacity = infallible(self.policy.cells_for(self.len().max(min_capacity)));
+        if capacity < self.capacity {
+            infallible(self.try_resize(capacity));
+        }
+    }
+
+    pub fn shrink_to_fit(&mut self) {
+        self.shrink_to(0);
+    }
+
+    pub fn len(&self) -> usize {
+        self.size + self.old.as_ref().map_or(0, |old| old.size)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn iter(&self) -> Iter<'_, K, V, H, A> {
+        Iter(self, 0, 0)
+    }
+
+    // The cells are swapped out for the duration of draining so that leaking the iterator
+    // leaves the map empty instead of with broken chains.
+    pub fn drain(&mut self) -> Drain<'_, K, V, H, A> {
+        self.finish_migration();
+        let ptr = mem::replace(&mut self.ptr, allocate(1, self.policy.store_hashes, &self.alloc));
+        let capacity = mem::replace(&mut self.capacity, 1);
+        let remaining = mem::replace(&mut self.size, 0);
+        Drain {
+            map: self,
+            ptr,
+            capacity,
+            remaining,
+            cell: 0,
+            slot: 0
+        }
+    }
+
+    // Keeps the allocated cells. Should dropping an entry panic, the rest of its cell is leaked.
+    pub fn clear(&mut self) {
+        // Empties every slot only once the entries are dropped, so that a panic does not leave
+        // chains running through slots that were already emptied.
+        struct Guard<'a, K: 'a, V: 'a, H: 'a, A: Allocator + 'a>(&'a mut HashMap<K, V, H, A>);
+
+        impl<'a, K: 'a, V: 'a, H: 'a, A: Allocator + 'a> Drop for Guard<'a, K, V, H, A> {
+            fn drop(&mut self) {
+                for cell in 0..self.0.capacity {
+                    unsafe { (*self.0.ptr.add(cell)).meta.clear() };
+                }
+                self.0.size = 0;
+            }
+        }
+
+        unsafe {
+            if let Some(old) = self.old.take() {
+                ptr::drop_in_place(ptr::slice_from_raw_parts_mut(old.ptr, old.capacity)); // NOTE: Possible panic
+                deallocate(old.ptr, old.capacity, self.policy.store_hashes, &self.alloc);
+            }
+            let guard = Guard(self);
+            ptr::drop_in_place(ptr::slice_from_raw_parts_mut(guard.0.ptr, guard.0.capacity)); // NOTE: Possible panic
+        }
+    }
+
+    pub fn retain<F>(&mut self, mut f: F)
+        where F: FnMut(&K, &mut V) -> bool
+    {
+        for _ in self.extract_if(|k, v| !f(k, v)) {}
+    }
+
+    pub fn extract_if<F>(&mut self, pred: F) -> ExtractIf<'_, K, V, H, F, A>
+        where F: FnMut(&K, &mut V) -> bool
+    {
+        self.finish_migration();
+        ExtractIf {
+            map: self,
+            pred,
+            cell: 0,
+            slot: 0,
+            chain: None
+        }
+    }
+
+    pub fn insert(&mut self, key: K, value: V) -> Option<(K, V)> {
+        self.grow_if_needed();
+        infallible(self.try_insert_grown(key, value).map_err(|error| error.error))
+    }
+
+    // Returns the same as `insert`, or the key and value if the map could not grow. Growing
+    // because the jumps ran out is reported as well. Buckets moved into the grown table are not
+    // checked again, so a hasher crowding keys into a few cells can still abort there.
+    pub fn try_insert(&mut self, key: K, value: V) -> Result<Option<(K, V)>, TryInsertError<K, V>> {
+        if let Err(error) = self.try_reserve(1) {
+            return Err(TryInsertError { error, key, value });
+        }
+        self.grow_if_needed();
+        self.try_insert_grown(key, value)
+    }
+
+    fn try_insert_grown(&mut self, key: K, value: V) -> Result<Option<(K, V)>, TryInsertError<K, V>> {
+        let hash = self.hash(&key) as usize; // NOTE: Possible panic
+        unsafe {
+            match self.probe_mut(hash, |k| *k == key) { // NOTE: Possible panic
+                Ok((_, pos)) => {
+                    let value_ptr = (&mut (*self.bucket_ptr(pos)).value) as *mut _;
+                    Ok(Some((key, ptr::replace(value_ptr, value))))
+                },
+                Err(vacancy) => match self.try_insert_vacant(hash, vacancy, Bucket::new(key, value)) {
+                    Ok(_) => Ok(None),
+                    Err((error, bucket)) => Err(TryInsertError { error, key: bucket.key, value: bucket.value }),
+                },
+            }
+        }
+    }
+
+    pub fn entry(&mut self, key: K) -> Entry<'_, K, V, H, A> {
+        self.grow_if_needed();
+        let hash = self.hash(&key) as usize; // NOTE: Possible panic
+        unsafe {
+            match self.probe_mut(hash, |k| *k == key) { // NOTE: Possible panic
+                Ok((prev, pos)) => Entry::Occupied(OccupiedEntry::new(self, prev, pos)),
+                Err(vacancy) => Entry::Vacant(VacantEntry::new(self, key, hash, vacancy)),
+            }
+        }
+    }
+
+    pub fn raw_entry(&self) -> RawEntryBuilder<'_, K, V, H, A> {
+        RawEntryBuilder::new(self)
+    }
+
+    pub fn raw_entry_mut(&mut self) -> RawEntryBuilderMut<'_, K, V, H, A> {
+        RawEntryBuilderMut::new(self)
+    }
+
+    pub fn hasher(&self) -> &H {
+        &self.hasher
+    }
+
+    fn grow_if_needed(&mut self) {
+        self.migrate(self.policy.migrate_cells);
+        if self.len() >= self.capacity() {
+            let new_capacity = infallible(self.policy.grown(self.capacity));
+            if self.policy.migrate_cells == 0 {
+                infallible(self.try_resize(new_capacity));
+            } else {
+                self.begin_migration(new_capacity);
+            }
+        }
+    }
+
+    fn begin_migration(&mut self, new_capacity: usize) {
+        // Only one table is migrated from at a time
+        self.finish_migration();
+        let ptr = mem::replace(&mut self.ptr, allocate(new_capacity, self.policy.store_hashes, &self.alloc));
+        let capacity = mem::replace(&mut self.capacity, new_capacity);
+        let size = mem::replace(&mut self.size, 0);
+        self.old = Some(OldCells {
+            ptr,
+            capacity,
+            size,
+            cell: 0
+        });
+    }
+
+    fn finish_migration(&mut self) {
+        while self.old.is_some() {
+            self.migrate(usize::MAX);
+        }
+    }
+
+    // Moves every chain starting in the next `cells` cells of the old table over.
+    // Chains are taken apart from their heads, so the rest of the old table stays searchable.
+    fn migrate(&mut self, mut cells: usize) {
+        while cells > 0 {
+            let cell = match self.old {
+                Some(ref old) if old.size > 0 => old.cell,
+                Some(_) => {
+                    let old = self.old.take().unwrap();
+                    unsafe { deallocate(old.ptr, old.capacity, self.policy.store_hashes, &self.alloc) };
+                    return;
+                },
+                None => return,
+            };
+            for slot in 0..BLOCK_SIZE {
+                let head = cell * BLOCK_SIZE + slot;
+                while let Some((stored, bucket)) = self.with_old(|map| unsafe { map.take_head(head) }) {
+                    unsafe { self.insert_unique(stored, bucket) };
+                    if self.old.is_none() {
+                        // Running out of jumps resized everything at once
+                        return;
+                    }
+                }
+            }
+            if let Some(ref mut old) = self.old {
+                old.cell += 1;
+                debug_assert!(old.cell < old.capacity || old.size == 0);
+            }
+            cells -= 1;
+        }
+    }
+
+    // Runs `f` with the old table swapped in place of the current one.
+    fn with_old<R, F>(&mut self, f: F) -> R
+        where F: FnOnce(&mut Self) -> R
+    {
+        let mut old = self.old.take().expect("no table is being migrated");
+        mem::swap(&mut self.ptr, &mut old.ptr);
+        mem::swap(&mut self.capacity, &mut old.capacity);
+        mem::swap(&mut self.size, &mut old.size);
+        let result = f(self);
+        mem::swap(&mut self.ptr, &mut old.ptr);
+        mem::swap(&mut self.capacity, &mut old.capacity);
+        mem::swap(&mut self.size, &mut old.size);
+        self.old = Some(old);
+        result
+    }
+
+    unsafe fn take_head(&mut self, head: usize) -> Option<(Option<usize>, Bucket<K, V>)> {
+        let mut meta = ptr::null();
+        self.get_data(head, &mut meta, &mut ptr::null());
+        if (*meta).is_empty() || (*meta).is_storage() {
+            None
+        } else {
+            let stored = self.stored_hash(head);
+            Some((stored, self.remove_at(head, head)))
+        }
+    }
+
+    // Walks the chain starting from `hash` until `eq` accepts a key.
+    // On success returns positions of the previous bucket in the chain and the found one.
+    unsafe fn probe<F>(&self, hash: usize, eq: F) -> Result<(usize, usize), Vacancy>
+        where F: FnMut(&K) -> bool
+    {
+        probe_cells(self.ptr, self.capacity, hash, eq)
+    }
+
+    // Like `probe`, but first moves a matching bucket out of the old table, so that it can be
+    // modified or removed in place.
+    unsafe fn probe_mut<F>(&mut self, hash: usize, mut eq: F) -> Result<(usize, usize), Vacancy>
+        where F: FnMut(&K) -> bool
+    {
+        let result = self.probe(hash, &mut eq);
+        let found = match self.old {
+            Some(ref old) if result.is_err() => probe_cells(old.ptr, old.capacity, hash, &mut eq).ok(),
+            _ => None,
+        };
+        match found {
+            Some((prev, pos)) => {
+                let bucket = self.with_old(|map| map.remove_at(prev, pos));
+                self.insert_hashed(hash, bucket);
+                self.probe(hash, eq)
+            },
+            None => result,
+        }
+    }
+
+    // Looks the key up from both the current and the old table.
+    unsafe fn find<F>(&self, hash: usize, mut eq: F) -> Option<*mut Bucket<K, V>>
+        where F: FnMut(&K) -> bool
+    {
+        if let Ok((_, pos)) = self.probe(hash, &mut eq) {
+            return Some(self.bucket_ptr(pos));
+        }
+        match self.old {
+            Some(ref old) => probe_cells(old.ptr, old.capacity, hash, eq).ok().map(|(_, pos)| cell_data(old.ptr, old.capacity, pos).1),
+            None => None,
+        }
+    }
+
+    // Inserts a bucket that was already in the map, using its stored hash if there is one.
+    unsafe fn insert_unique(&mut self, stored: Option<usize>, bucket: Bucket<K, V>) -> *mut Bucket<K, V> {
+        let hash = match stored {
+            Some(hash) => hash,
+            None => self.hash(&bucket.key) as usize, // NOTE: Possible panic
+        };
+        self.insert_hashed(hash, bucket)
+    }
+
+    unsafe fn insert_hashed(&mut self, hash: usize, bucket: Bucket<K, V>) -> *mut Bucket<K, V> {
+        let vacancy = self.probe(hash, |_| false).unwrap_err();
+        self.insert_vacant(hash, vacancy, bucket)
+    }
+
+    unsafe fn try_insert_hashed(&mut self, hash: usize, bucket: Bucket<K, V>) -> InsertResult<K, V> {
+        let vacancy = self.probe(hash, |_| false).unwrap_err();
+        self.try_insert_vacant(hash, vacancy, bucket)
+    }
+
+    unsafe fn insert_vacant(&mut self, hash: usize, vacancy: Vacancy, bucket: Bucket<K, V>) -> *mut Bucket<K, V> {
+        infallible(self.try_insert_vacant(hash, vacancy, bucket).map_err(|(error, _)| error))
+    }
+
+    // Running out of jumps grows the map. If that fails, the table is left as it was and the
+    // bucket is handed back.
+    unsafe fn try_insert_vacant(&mut self, hash: usize, vacancy: Vacancy, bucket: Bucket<K, V>) -> InsertResult<K, V> {
+        let mut cur_meta = ptr::null_mut();
+        let mut data_ptr = ptr::null_mut();
+        let mut pos = hash;
+        match vacancy {
+            Vacancy::Empty => {
+                self.mut_data(hash, &mut cur_meta, &mut data_ptr);
+                (*cur_meta).set_last(false);
+            },
+            Vacancy::Storage => {
+                self.mut_data(hash, &mut cur_meta, &mut data_ptr);
+                let prev_hash = self.find_previous(hash, data_ptr).expect("hash given to a raw entry does not match its key");
+                match self.claim_relocation(prev_hash, hash) {
+                    Some(first_jump) => self.relocate(prev_hash, first_jump, hash),
+                    None => {
+                        if let Err(error) = self.try_reallocate() {
+                            return Err((error, bucket));
+                        }
+                        return self.try_insert_hashed(hash, bucket);
+                    },
+                }
+                (*cur_meta).set_last(false);
+            },
+            Vacancy::Tail(last) => {
+                let mut prev_meta = ptr::null_mut();
+                self.mut_data(last, &mut prev_meta, &mut ptr::null_mut());
+                if let Some((empty_ptr, jumps)) = self.find_empty(last, 1, &mut cur_meta) {
+                    (*cur_meta).set_last(true);
+                    (*prev_meta).set_jump(jumps);
+                    data_ptr = empty_ptr;
+                    pos = last.wrapping_add(JUMP_DISTANCES[jumps as usize]);
+                } else {
+                    if let Err(error) = self.try_reallocate() {
+                        return Err((error, bucket));
+                    }
+                    return self.try_insert_hashed(hash, bucket);
+                }
+            }
+        }
+        ptr::write(data_ptr, bucket);
+        self.store_hash(pos, hash);
+        self.size += 1;
+        Ok(data_ptr)
+    }
+
+    // Claims an empty slot for every bucket of the chain passing through `pos`, from `pos` on,
+    // and links the claimed slots the way the chain will be linked once moved. Nothing is moved
+    // yet, so that running out of jumps can undo the claims and leave the table as it was.
+    // Returns the jump from `prev_hash` to the first claimed slot.
+    unsafe fn claim_relocation(&mut self, prev_hash: usize, pos: usize) -> Option<u8> {
+        let mut prev_meta = ptr::null_mut();
+        self.mut_data(prev_hash, &mut prev_meta, &mut ptr::null_mut());
+        let mut start = (*prev_meta).jump_length();
+        let (mut from, mut to) = (pos, prev_hash);
+        let mut first_jump = None;
+        let mut last_claim: *mut Metadata = ptr::null_mut();
+        loop {
+            let mut claim = ptr::null_mut();
+            match self.find_empty(to, start, &mut claim) {
+                Some((_, jumps)) => {
+                    (*claim).set_last(true);
+                    match first_jump {
+                        None => first_jump = Some(jumps),
+                        Some(_) => (*last_claim).set_jump(jumps),
+                    }
+                    last_claim = claim;
+                    to = to.wrapping_add(JUMP_DISTANCES[jumps as usize]);
+                    start = 1;
+                },
+                None => {
+                    if let Some(jumps) = first_jump {
+                        self.unclaim(prev_hash.wrapping_add(JUMP_DISTANCES[jumps as usize]));
+                    }
+                    return None;
+                },
+            }
+            let mut from_meta = ptr::null_mut();
+            self.mut_data(from, &mut from_meta, &mut ptr::null_mut());
+            let jump = (*from_meta).jump_length();
+            if jump == 0 {
+                return first_jump;
+            }
+            from = from.wrapping_add(JUMP_DISTANCES[jump as usize]);
+        }
+    }
+
+    unsafe fn unclaim(&mut self, mut pos: usize) {
+        loop {
+            let mut meta = ptr::null_mut();
+            self.mut_data(pos, &mut meta, &mut ptr::null_mut());
+            let jump = (*meta).jump_length();
+            (*meta).set_empty();
+            if jump == 0 {
+                break;
+            }
+            pos = pos.wrapping_add(JUMP_DISTANCES[jump as usize]);
+        }
+    }
+
+    // Moves the chain passing through `pos` into the slots claimed for it. The slot at `pos` is
+    // left taken for the bucket that displaced the chain.
+    unsafe fn relocate(&mut self, prev_hash: usize, first_jump: u8, pos: usize) {
+        let (mut prev_meta, mut from_meta, mut to_meta) = (ptr::null_mut(), ptr::null_mut(), ptr::null_mut());
+        let (mut from_ptr, mut to_ptr) = (ptr::null_mut(), ptr::null_mut());
+        self.mut_data(prev_hash, &mut prev_meta, &mut ptr::null_mut());
+        (*prev_meta).set_jump(first_jump);
+        let (mut from, mut to) = (pos, prev_hash.wrapping_add(JUMP_DISTANCES[first_jump as usize]));
+        loop {
+            self.mut_data(from, &mut from_meta, &mut from_ptr);
+            self.mut_data(to, &mut to_meta, &mut to_ptr);
+            ptr::copy_nonoverlapping(from_ptr, to_ptr, 1);
+            if let Some(moved_hash) = self.stored_hash(from) {
+                self.store_hash(to, moved_hash);
+            }
+            let jump = (*from_meta).jump_length();
+            if from != pos {
+                (*from_meta).set_empty();
+            }
+            if jump == 0 {
+                break;
+            }
+            from = from.wrapping_add(JUMP_DISTANCES[jump as usize]);
+            to = to.wrapping_add(JUMP_DISTANCES[(*to_meta).jump_length() as usize]);
+        }
+    }
+
+    // Finds the slot linking to the storage slot at `target_hash` by following the chain of the
+    // bucket's key. Returns `None` if that chain does not pass through it, which only happens
+    // when the bucket was inserted through a raw entry with a wrong hash.
+    unsafe fn find_previous(&self, target_hash: usize, data_ptr: *const Bucket<K, V>) -> Option<usize> {
+        let mut their_hash = match self.stored_hash(target_hash) {
+            Some(hash) => hash,
+            None => self.hash(&(*data_ptr).key) as usize, // NOTE: Possible panic
+        };
+        let mut prev_hash = None;
+        let mut before_meta = ptr::null();
+        while split_hash(their_hash, self.capacity) != split_hash(target_hash, self.capacity) {
+            self.get_data(their_hash, &mut before_meta, &mut ptr::null());
+            if (*before_meta).is_empty() || (*before_meta).jump_length() == 0 {
+                return None;
+            }
+            prev_hash = Some(their_hash);
+            their_hash = their_hash.wrapping_add(JUMP_DISTANCES[(*before_meta).jump_length() as usize]);
+        }
+        prev_hash
+    }
+
+    unsafe fn find_empty<'b>(&mut self, hash: usize, start: u8, meta: &'b mut *mut Metadata) -> Option<(*mut Bucket<K, V>, u8)> {
+        let mut data_ptr = ptr::null_mut();
+        let (start, max_jump) = (start as usize, self.policy.max_jump as usize);
+        let near = cmp::min(max_jump, BLOCK_SIZE - 1);
+        if start <= near {
+            // Jumps shorter than a cell only reach this and the next cell, so both are checked at once.
+            let (cell, slot) = split_hash(hash, self.capacity);
+            let (next, _) = split_hash(hash.wrapping_add(BLOCK_SIZE), self.capacity);
+            let empty = (*self.ptr.offset(cell)).meta.empty_mask() as u32
+                | ((*self.ptr.offset(next)).meta.empty_mask() as u32) << BLOCK_SIZE;
+            let reachable = (1 << (near + 1)) - (1 << start);
+            if let Some(jumps) = BitMask(((empty >> slot) & reachable) as u16).next() {
+                self.mut_data(hash.wrapping_add(jumps), meta, &mut data_ptr);
+                return Some((data_ptr, jumps as u8));
+            }
+        }
+        for jumps in cmp::max(start, near + 1)..=max_jump {
+            let new_hash = hash.wrapping_add(JUMP_DISTANCES[jumps]);
+
+            self.mut_data(new_hash, meta, &mut data_ptr);
+
+            if (**meta).is_empty() {
+                return Some((data_ptr, jumps as u8));
+            }
+        }
+        None
+    }
+
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<(K, V)>
+        where Q: ?Sized + Hash + Equivalent<K>
+    {
+        self.migrate(self.policy.migrate_cells);
+        let hash = self.hash(key) as usize; // NOTE: Possible panic
+        unsafe {
+            match self.probe_mut(hash, |k| key.equivalent(k)) { // NOTE: Possible panic
+                Ok((prev, pos)) => {
+                    let bucket = self.remove_at(prev, pos);
+                    Some((bucket.key, bucket.value))
+                },
+                Err(_) => None,
+            }
+        }
+    }
+
+    unsafe fn remove_at(&mut self, prev: usize, pos: usize) -> Bucket<K, V> {
+        let mut prev_meta = ptr::null_mut();
+        let mut cur_meta = ptr::null_mut();
+        let mut data_ptr = ptr::null_mut();
+        self.mut_data(prev, &mut prev_meta, &mut ptr::null_mut());
+        self.mut_data(pos, &mut cur_meta, &mut data_ptr);
+
+        let data = ptr::read(data_ptr);
+        let mut hash = pos;
+        let mut prev_ptr;
+        loop {
+            let jump = (*cur_meta).jump_length();
+            if jump == 0 {
+                (*prev_meta).set_jump(0);
+                (*cur_meta).set_empty();
+                break;
+            }
+            prev_ptr = data_ptr;
+            prev_meta = cur_meta;
+            let prev_hash = hash;
+            hash = hash.wrapping_add(JUMP_DISTANCES[jump as usize]);
+            self.mut_data(hash, &mut cur_meta, &mut data_ptr);
+            ptr::write(prev_ptr, ptr::read(data_ptr));
+            if let Some(moved_hash) = self.stored_hash(hash) {
+                self.store_hash(prev_hash, moved_hash);
+            }
+        }
+        self.size -= 1;
+        data
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<&V>
+        where Q: ?Sized + Hash + Equivalent<K>
+    {
+        let hash = self.hash(key) as usize; // NOTE: Possible panic
+        unsafe {
+            self.find(hash, |k| key.equivalent(k)).map(|bucket| &(*bucket).value) // NOTE: Possible panic
+        }
+    }
+
+    pub fn get_key_value<Q>(&self, key: &Q) -> Option<(&K, &V)>
+        where Q: ?Sized + Hash + Equivalent<K>
+    {
+        let hash = self.hash(key) as usize; // NOTE: Possible panic
+        unsafe {
+            self.find(hash, |k| key.equivalent(k)).map(|bucket| (&(*bucket).key, &(*bucket).value)) // NOTE: Possible panic
+        }
+    }
+
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+        where Q: ?Sized + Hash + Equivalent<K>
+    {
+        let hash = self.hash(key) as usize; // NOTE: Possible panic
+        unsafe {
+            match self.probe_mut(hash, |k| key.equivalent(k)) { // NOTE: Possible panic
+                Ok((_, pos)) => Some(&mut (*self.bucket_ptr(pos)).value),
+                Err(_) => None,
+            }
+        }
+    }
+
+    // Returns `None` if a key is missing or two keys are the same.
+    pub fn get_many_mut<Q, const N: usize>(&mut self, keys: [&Q; N]) -> Option<[&mut V; N]>
+        where Q: ?Sized + Hash + Equivalent<K>
+    {
+        unsafe {
+            let ptrs = self.get_many_ptrs(keys)?;
+            for (i, ptr) in ptrs.iter().enumerate() {
+                if ptrs[..i].contains(ptr) {
+                    return None;
+                }
+            }
+            Some(ptrs.map(|ptr| &mut *ptr))
+        }
+    }
+
+    // Same as `get_many_mut`, without checking that the keys are different.
+    /// # Safety
+    ///
+    /// No two keys may find the same entry, as that would hand out two mutable references to
+    /// one value.
+    pub unsafe fn get_many_unchecked_mut<Q, const N: usize>(&mut self, keys: [&Q; N]) -> Option<[&mut V; N]>
+        where Q: ?Sized + Hash + Equivalent<K>
+    {
+        self.get_many_ptrs(keys).map(|ptrs| ptrs.map(|ptr| &mut *ptr))
+    }
+
+    // Keys are first moved out of the old table, as moving one can relocate the others.
+    unsafe fn get_many_ptrs<Q, const N: usize>(&mut self, keys: [&Q; N]) -> Option<[*mut V; N]>
+        where Q: ?Sized + Hash + Equivalent<K>
+    {
+        let hashes = keys.map(|key| self.hash(key) as usize); // NOTE: Possible panic
+        if self.old.is_some() {
+            for (key, &hash) in keys.iter().zip(&hashes) {
+                self.probe_mut(hash, |k| key.equivalent(k)).ok()?; // NOTE: Possible panic
+            }
+        }
+        let mut ptrs = [ptr::null_mut(); N];
+        for ((key, &hash), ptr) in keys.iter().zip(&hashes).zip(&mut ptrs) {
+            let (_, pos) = self.probe(hash, |k| key.equivalent(k)).ok()?; // NOTE: Possible panic
+            *ptr = &mut (*self.bucket_ptr(pos)).value as *mut V;
+        }
+        Some(ptrs)
+    }
+
+    fn try_reallocate(&mut self) -> Result<(), TryReserveError> {
+        let new_capacity = self.policy.grown(self.capacity)?;
+        self.try_resize(new_capacity)
+    }
+
+    // Moves everything over at once, including what an incremental resize has not moved yet.
+    fn try_resize(&mut self, new_capacity: usize) -> Result<(), TryReserveError> {
+        let new_ptr = try_allocate(new_capacity, self.policy.store_hashes, &self.alloc)?;
+        let old_capacity = mem::replace(&mut self.capacity, new_capacity);
+        let old_ptr = mem::replace(&mut self.ptr, new_ptr);
+        let migrating = self.old.take().map_or((ptr::null_mut(), 0), |old| (old.ptr, old.capacity));
+        self.size = 0;
+
+        let evacuated = Evacuated {
+            map: self,
+            cells: [(old_ptr, old_capacity), migrating]
+        };
+        for &(ptr, capacity) in &evacuated.cells {
+            unsafe { evacuated.map.reinsert_all(ptr, capacity) };
+        }
+        Ok(())
+    }
+
+    // Chains of the given cells do not need to be intact, since every bucket is rehashed.
+    // Slots are emptied before their buckets are moved out, so a panic cannot duplicate them.
+    unsafe fn reinsert_all(&mut self, ptr: *mut Cell<K, V>, capacity: usize) {
+        for cell in 0..capacity {
+            let cur_cell = ptr.add(cell);
+            for slot in BitMask((*cur_cell).meta.full_mask()) {
+                (*cur_cell).meta.0[slot].set_empty();
+                let data_ptr = (ptr::addr_of!((*cur_cell).data) as *const Bucket<K, V>).add(slot);
+                let stored = stored_hash(ptr, capacity, self.policy.store_hashes, cell * BLOCK_SIZE + slot);
+                self.insert_unique(stored, ptr::read(data_ptr));
+            }
+        }
+    }
+
+    fn stored_hash(&self, pos: usize) -> Option<usize> {
+        unsafe { stored_hash(self.ptr, self.capacity, self.policy.store_hashes, pos) }
+    }
+
+    fn store_hash(&mut self, pos: usize, hash: usize) {
+        if self.policy.store_hashes {
+            unsafe { *hash_slot(self.ptr, self.capacity, pos) = hash };
+        }
+    }
+
+    fn hash<Q: ?Sized + Hash>(&self, key: &Q) -> u64 {
+        let mut state = self.hasher.build_hasher();
+        key.hash(&mut state);
+        state.finish()
+    }
+
+    fn bucket_ptr(&self, hash: usize) -> *mut Bucket<K, V> {
+        unsafe {
+            let (cell, slot) = split_hash(hash, self.capacity);
+            let cur_cell = &*self.ptr.offset(cell);
+            cur_cell.data.0.as_ptr().add(slot) as *mut _
+        }
+    }
+
+    fn get_data(&self, hash: usize, cur_meta: &mut *const Metadata, data_ptr: &mut *const Bucket<K, V>) {
+        let (meta, data) = unsafe { cell_data(self.ptr, self.capacity, hash) };
+        *cur_meta = meta;
+        *data_ptr = data;
+    }
+    
+    // TODO: Abstract over mutability. Needs HKT/GAT
+    fn mut_data(&mut self, hash: usize, cur_meta: &mut *mut Metadata, data_ptr: &mut *mut Bucket<K, V>) {
+        let (meta, data) = unsafe { cell_data(self.ptr, self.capacity, hash) };
+        *cur_meta = meta;
+        *data_ptr = data;
+    }
+
+    #[cfg(test)]
+    fn debug(&self) {
+        unsafe {
+            let numbers = (self.capacity as f32).log(10.) as usize + 1;
+            for cell in 0..self.capacity {
+                let cur_cell = self.ptr.offset(cell as isize);
+                print!("{:w$} ", cell, w = numbers);
+                for slot in 0..BLOCK_SIZE {
+                    print!("{:?}", (*cur_cell).meta.0[slot]);
+                }
+                print!(" ");
+                if cell % 2 == 1 {
+                    println!();
+                }
+            }
+            if self.capacity % 2 == 1 {
+                println!();
+            }
+        }
+    }
+}
+
+fn allocate<K, V, A: Allocator>(capacity: usize, hashes: bool, alloc: &A) -> *mut Cell<K, V> {
+    infallible(try_allocate(capacity, hashes, alloc))
+}
+
+fn try_allocate<K, V, A: Allocator>(capacity: usize, hashes: bool, alloc: &A) -> Result<*mut Cell<K, V>, TryReserveError> {
+    let layout = table_layout::<K, V>(capacity, hashes)?;
+    unsafe {
+        let ptr = match alloc.allocate(layout) {
+            Ok(ptr) => ptr.cast::<Cell<K, V>>().as_ptr(),
+            Err(_) => return Err(TryReserveError::AllocError { layout }),
+        };
+        for cell in 0..capacity {
+            let cur_cell = ptr.add(cell);
+            ptr::write(ptr::addr_of_mut!((*cur_cell).meta), Metadatum::default());
+        }
+        Ok(ptr)
+    }
+}
+
+// Metadata of a slot is copied only after its bucket is written, so that a panic leaves the
+// target cells droppable.
+unsafe fn clone_cells<K: Clone, V: Clone>(src: *mut Cell<K, V>, dst: *mut Cell<K, V>, capacity: usize, hashes: bool) {
+    for cell in 0..capacity {
+        let (src_cell, dst_cell) = (&*src.add(cell), &mut *dst.add(cell));
+        for slot in BitMask(src_cell.meta.full_mask()) {
+            let bucket = &*src_cell.data.0.as_ptr().add(slot);
+            let clone = Bucket::new(bucket.key.clone(), bucket.value.clone()); // NOTE: Possible panic
+            ptr::write(dst_cell.data.0.as_mut_ptr().add(slot), clone);
+            dst_cell.meta.0[slot] = Metadata(src_cell.meta.0[slot].0);
+            if hashes {
+                let pos = cell * BLOCK_SIZE + slot;
+                *hash_slot(dst, capacity, pos) = *hash_slot(src, capacity, pos);
+            }
+        }
+    }
+}
+
+// Cells have to be dropped beforehand if they can contain something.
+unsafe fn deallocate<K, V, A: Allocator>(ptr: *mut Cell<K, V>, capacity: usize, hashes: bool, alloc: &A) {
+    let layout = table_layout::<K, V>(capacity, hashes).unwrap();
+    alloc.deallocate(NonNull::new_unchecked(ptr as *mut u8), layout);
+}
+
+// Stored hashes follow the cells in the same allocation, one for each slot.
+fn table_layout<K, V>(capacity: usize, hashes: bool) -> Result<Layout, TryReserveError> {
+    let cells = Layout::array::<Cell<K, V>>(capacity).map_err(|_| TryReserveError::CapacityOverflow)?;
+    if !hashes {
+        return Ok(cells);
+    }
+    capacity.checked_mul(BLOCK_SIZE)
+        .and_then(|slots| Layout::array::<usize>(slots).ok())
+        .and_then(|hashes| cells.extend(hashes).ok())
+        .map(|(layout, _)| layout)
+        .ok_or(TryReserveError::CapacityOverflow)
+}
+
+unsafe fn hash_slot<K, V>(ptr: *mut Cell<K, V>, capacity: usize, pos: usize) -> *mut usize {
+    let align = mem::align_of::<usize>();
+    let offset = (capacity * mem::size_of::<Cell<K, V>>() + align - 1) & !(align - 1);
+    let (cell, slot) = split_hash(pos, capacity);
+    (ptr as *mut u8).add(offset).cast::<usize>().add(cell as usize * BLOCK_SIZE + slot)
+}
+
+unsafe fn stored_hash<K, V>(ptr: *mut Cell<K, V>, capacity: usize, hashes: bool, pos: usize) -> Option<usize> {
+    if hashes {
+        Some(*hash_slot(ptr, capacity, pos))
+    } else {
+        None
+    }
+}
+
+fn infallible<T>(result: Result<T, TryReserveError>) -> T {
+    match result {
+        Ok(value) => value,
+        Err(TryReserveError::CapacityOverflow) => panic!("capacity overflow"),
+        Err(TryReserveError::AllocError { layout }) => handle_alloc_error(layout),
+    }
+}
+
+// Same as `HashMap::probe` for cells that are not the map's current ones.
+unsafe fn probe_cells<K, V, F>(ptr: *mut Cell<K, V>, capacity: usize, hash: usize, mut eq: F) -> Result<(usize, usize), Vacancy>
+    where F: FnMut(&K) -> bool
+{
+    let (mut cur_meta, mut data_ptr) = cell_data(ptr, capacity, hash);
+
+    if (*cur_meta).is_empty() {
+        return Err(Vacancy::Empty);
+    } else if (*cur_meta).is_storage() {
+        return Err(Vacancy::Storage);
+    }
+    let (mut prev_hash, mut hash) = (hash, hash);
+    loop {
+        if eq(&(*data_ptr).key) {
+            return Ok((prev_hash, hash));
+        }
+        let jump = (*cur_meta).jump_length();
+        if jump == 0 {
+            return Err(Vacancy::Tail(hash));
+        }
+        prev_hash = hash;
+        hash = hash.wrapping_add(JUMP_DISTANCES[jump as usize]);
+        let (meta, data) = cell_data(ptr, capacity, hash);
+        cur_meta = meta;
+        data_ptr = data;
+    }
+}
+
+unsafe fn cell_data<K, V>(ptr: *mut Cell<K, V>, capacity: usize, hash: usize) -> (*mut Metadata, *mut Bucket<K, V>) {
+    let (cell, slot) = split_hash(hash, capacity);
+    let cur_cell = ptr.offset(cell);
+    let meta_ptr = ptr::addr_of_mut!((*cur_cell).meta.0) as *mut Metadata;
+    let datum_ptr = ptr::addr_of_mut!((*cur_cell).data) as *mut Bucket<K, V>;
+    (meta_ptr.add(slot), datum_ptr.add(slot))
+}
+
+unsafe fn take_next<K, V>(ptr: *mut Cell<K, V>, capacity: usize, cell: &mut usize, slot: &mut usize) -> Option<Bucket<K, V>> {
+    while *cell < capacity {
+        let cur_cell = &mut *ptr.add(*cell);
+        if let Some(cur_slot) = cur_cell.meta.next_full(cell, slot) {
+            cur_cell.meta.0[cur_slot].set_empty();
+            let datum_ptr = cur_cell.data.0.as_ptr();
+            return Some(ptr::read(datum_ptr.add(cur_slot)));
+        }
+    }
+    None
+}
+
+fn split_hash(hash: usize, capacity: usize) -> (isize, usize) {
+    (
+        ((hash / BLOCK_SIZE) & (capacity - 1)) as isize,
+        hash & (BLOCK_SIZE - 1)
+    )
+}
+
+#[test]
+fn adding_one_works() {
+    let max = 10000;
+    for n in 0..max {
+        let mut map = HashMap::with_hasher(::fnv::FnvBuildHasher::default());
+        map.insert(n, n);
+        assert_eq!(Some(&n), map.get(&n));
+    }
+}
+
+#[test]
 fn adding_multiple_works() {
     let max = 10000;
     let mut map = HashMap::with_hasher(::fnv::FnvBuildHasher::default());
@@ -561,67 +1614,776 @@ fn adding_multiple_works() {
         map.insert(n, n);
     }
     for n in 0..max {
-        let val = map.get(&n);
-        if Some(&n) != val {
-            map.debug();
-            panic!("Getting {} at {:?} failed. Was: {:?}", n, split_hash(map.hash(&n) as usize, map.capacity), val);
+        let val = map.get(&n);
+        if Some(&n) != val {
+            map.debug();
+            panic!("Getting {} at {:?} failed. Was: {:?}", n, split_hash(map.hash(&n) as usize, map.capacity), val);
+        }
+    }
+}
+
+#[test]
+fn adding_random_works() {
+    use rand::rngs::SmallRng;
+    use rand::distributions::Standard;
+    use rand::{SeedableRng, Rng};
+    let max = 10000;
+    let mut map = HashMap::<u64, u64, _>::with_hasher(::fnv::FnvBuildHasher::default());
+    let mut rng = SmallRng::from_seed([0, 1, 1, 2, 3, 5, 8, 13, 21, 34, 55, 89, 144, 233, 3, 5]);
+    let mut rng2 = rng.clone();
+    for n in rng.sample_iter(&Standard).take(max) {
+        map.insert(n, n);
+    }
+    for n in rng2.sample_iter(&Standard).take(max) {
+        let val = map.get(&n);
+        if Some(&n) != val {
+            map.debug();
+            panic!("Getting {} at {:?} failed. Was: {:?}", n, split_hash(map.hash(&n) as usize, map.capacity), val);
+        }
+    }
+}
+
+#[test]
+fn removing_works() {
+    let max = 10000;
+    let mut map = HashMap::with_hasher(::fnv::FnvBuildHasher::default());
+    for n in 0..max {
+        map.insert(n, n);
+    }
+    for n in 0..max {
+        let val = map.remove(&n);
+        if Some((n, n)) != val {
+            map.debug();
+            panic!("Removing {} at {:?} failed. Was: {:?}", n, split_hash(map.hash(&n) as usize, map.capacity), val);
+        }
+    }
+    for n in 0..max {
+        assert!(map.get(&n).is_none());
+    }
+}
+
+// Relocating a chain out of the home slot of a new key used to move a later bucket of the same
+// chain into that home slot, where the new bucket then overwrote it.
+#[test]
+fn relocating_out_of_home_slot_works() {
+    use rand::rngs::SmallRng;
+    use rand::{Rng, SeedableRng};
+    use std::collections::HashMap as StdHashMap;
+
+    for seed in 1..20 {
+        let mut rng = SmallRng::from_seed([seed; 16]);
+        let mut map = HashMapBuilder::new(BuildColliding::default())
+            .incremental_resize(1)
+            .build();
+        let mut model = StdHashMap::new();
+        for step in 0..1000 {
+            let key = rng.gen_range(0, 200);
+            match rng.gen_range(0, 20) {
+                0..=8 => assert_eq!(model.insert(key, step).map(|v| (key, v)), map.insert(key, step)),
+                9..=14 => assert_eq!(model.remove(&key).map(|v| (key, v)), map.remove(&key)),
+                15..=18 => assert_eq!(model.get(&key), map.get(&key)),
+                _ => map.shrink_to_fit(),
+            }
+            assert_eq!(model.len(), map.iter().count(), "seed {} step {}", seed, step);
+        }
+    }
+}
+
+#[test]
+fn iterator_works() {
+    use std::collections::HashMap as HMap;
+    let max = 10000;
+    let mut map = HashMap::with_hasher(::fnv::FnvBuildHasher::default());
+    let mut added = HMap::new();
+    for n in 0..max {
+        map.insert(n, n);
+        added.insert(n, n);
+    }
+
+    for (k, v) in &map {
+        assert_eq!(Some(v), added.remove(k).as_ref());
+    }
+    assert!(added.is_empty());
+}
+
+#[test]
+fn entry_counting_works() {
+    let max = 10000;
+    let mut map = HashMap::with_hasher(::fnv::FnvBuildHasher::default());
+    for n in 0..max {
+        *map.entry(n % 100).or_insert(0) += 1;
+    }
+    for n in 0..100 {
+        assert_eq!(Some(&(max / 100)), map.get(&n));
+    }
+}
+
+#[test]
+fn entry_inserting_works() {
+    let max = 10000;
+    let mut map = HashMap::with_hasher(::fnv::FnvBuildHasher::default());
+    for n in 0..max {
+        match map.entry(n) {
+            Entry::Occupied(_) => panic!("{} was already present", n),
+            Entry::Vacant(e) => assert_eq!(n, *e.insert(n)),
+        }
+    }
+    for n in 0..max {
+        map.entry(n).and_modify(|v| *v += 1).or_insert(0);
+    }
+    for n in 0..max {
+        assert_eq!(Some(&(n + 1)), map.get(&n));
+    }
+}
+
+#[test]
+fn entry_removing_works() {
+    let max = 10000;
+    let mut map = HashMap::with_hasher(::fnv::FnvBuildHasher::default());
+    for n in 0..max {
+        map.insert(n, n);
+    }
+    for n in 0..max {
+        match map.entry(n) {
+            Entry::Occupied(e) => assert_eq!((n, n), e.remove_entry()),
+            Entry::Vacant(_) => panic!("{} was missing", n),
         }
     }
+    for n in 0..max {
+        assert!(map.get(&n).is_none());
+    }
 }
 
 #[test]
-fn adding_random_works() {
-    use rand::rngs::SmallRng;
-    use rand::distributions::Standard;
-    use rand::{SeedableRng, Rng};
+fn borrowed_lookup_works() {
+    let max = 1000;
+    let mut map = HashMap::with_hasher(::fnv::FnvBuildHasher::default());
+    for n in 0..max {
+        map.insert(n.to_string(), n);
+    }
+    for n in 0..max {
+        assert_eq!(Some(&n), map.get(&*n.to_string()));
+        *map.get_mut(&*n.to_string()).unwrap() += 1;
+    }
+    for n in 0..max {
+        assert_eq!(Some((n.to_string(), n + 1)), map.remove(&*n.to_string()));
+    }
+    assert!(map.get("0").is_none());
+}
+
+#[test]
+fn equivalent_lookup_works() {
+    #[derive(Hash)]
+    struct Query<'a>(&'a str, u32);
+
+    impl<'a> Equivalent<(String, u32)> for Query<'a> {
+        fn equivalent(&self, key: &(String, u32)) -> bool {
+            self.0 == key.0 && self.1 == key.1
+        }
+    }
+
+    let max = 1000;
+    let mut map = HashMap::with_hasher(::fnv::FnvBuildHasher::default());
+    for n in 0..max {
+        map.insert((n.to_string(), n), n);
+    }
+    for n in 0..max {
+        assert_eq!(Some(&n), map.get(&Query(&n.to_string(), n)));
+        assert!(map.get(&Query(&n.to_string(), n + 1)).is_none());
+        assert_eq!(Some(&n), map.get(&Composite((&*n.to_string(), &n))));
+        assert!(map.get(&Composite((&*n.to_string(), &(n + 1)))).is_none());
+    }
+}
+
+#[test]
+fn into_iterator_works() {
+    use std::collections::HashMap as HMap;
     let max = 10000;
-    let mut map = HashMap::<u64, u64, _>::with_hasher(::fnv::FnvBuildHasher::default());
-    let mut rng = SmallRng::from_seed([0, 1, 1, 2, 3, 5, 8, 13, 21, 34, 55, 89, 144, 233, 3, 5]);
-    let mut rng2 = rng.clone();
-    for n in rng.sample_iter(&Standard).take(max) {
+    let mut map = HashMap::with_hasher(::fnv::FnvBuildHasher::default());
+    let mut added = HMap::new();
+    for n in 0..max {
+        map.insert(n, n.to_string());
+        added.insert(n, n.to_string());
+    }
+
+    let mut iter = map.into_iter();
+    assert_eq!((max, Some(max)), iter.size_hint());
+    for (k, v) in iter.by_ref().take(max / 2) {
+        assert_eq!(Some(v), added.remove(&k));
+    }
+    assert_eq!(max - max / 2, iter.count());
+}
+
+#[test]
+fn draining_works() {
+    use std::rc::Rc;
+    let max = 10000;
+    let counter = Rc::new(());
+    let mut map = HashMap::with_hasher(::fnv::FnvBuildHasher::default());
+    for n in 0..max {
+        map.insert(n, counter.clone());
+    }
+    let capacity = map.capacity;
+
+    assert_eq!(max / 2, map.drain().take(max / 2).count());
+    assert_eq!(1, Rc::strong_count(&counter));
+    assert!(map.is_empty());
+    assert_eq!(capacity, map.capacity);
+    for n in 0..max {
+        assert!(map.get(&n).is_none());
+    }
+
+    for n in 0..max {
+        map.insert(n, counter.clone());
+    }
+    assert_eq!(capacity, map.capacity);
+    assert_eq!(max, map.drain().count());
+    drop(map);
+    assert_eq!(1, Rc::strong_count(&counter));
+}
+
+#[test]
+fn getting_many_mut_works() {
+    let max = 1000;
+    for mut map in HashMapBuilder::new(::fnv::FnvBuildHasher::default()).build_both() {
+        for n in 0..max {
+            map.insert(n, n);
+        }
+        for n in 0..max - 1 {
+            let [from, to] = map.get_many_mut([&n, &(n + 1)]).unwrap();
+            *to += *from;
+            *from = 0;
+        }
+        assert_eq!(Some(&(max * (max - 1) / 2)), map.get(&(max - 1)));
+        assert_eq!(0, map.iter().filter(|&(&k, _)| k != max - 1).map(|(_, &v)| v).sum::<u32>());
+        assert!(map.get_many_mut([&1, &2, &1]).is_none());
+        assert!(map.get_many_mut([&1, &max]).is_none());
+        assert_eq!(Some([&mut 0, &mut 0, &mut 0]), map.get_many_mut([&3, &1, &2]));
+        assert_eq!(Some([&mut 0]), unsafe { map.get_many_unchecked_mut([&5]) });
+    }
+}
+
+#[test]
+fn cloning_works() {
+    let max = 10000;
+    for &(incremental, store_hashes) in &[(false, false), (true, false), (true, true)] {
+        let builder = HashMapBuilder::new(::fnv::FnvBuildHasher::default()).store_hashes(store_hashes);
+        let mut map = if incremental { builder.incremental_resize(1).build() } else { builder.build() };
+        for n in 0..max {
+            map.insert(n, n.to_string());
+        }
+        let mut clone = map.clone();
+        assert_eq!(map.capacity, clone.capacity);
+        assert_eq!(map.stats(), clone.stats());
+        assert_eq!(map, clone);
+        clone.insert(max, max.to_string());
+        assert!(map != clone);
+        clone.remove(&max);
+        *clone.get_mut(&0).unwrap() = String::new();
+        assert!(map != clone);
+        for n in 1..max {
+            assert_eq!(Some(&n.to_string()), clone.get(&n));
+        }
+    }
+}
+
+#[test]
+fn panicking_clone_is_safe() {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use std::rc::Rc;
+
+    struct Fragile(u32, Rc<()>);
+
+    impl Clone for Fragile {
+        fn clone(&self) -> Self {
+            if self.0 == 500 {
+                panic!("clone");
+            }
+            Fragile(self.0, self.1.clone())
+        }
+    }
+
+    let counter = Rc::new(());
+    let mut map = HashMap::with_hasher(::fnv::FnvBuildHasher::default());
+    for n in 0..1000 {
+        map.insert(n, Fragile(n, counter.clone()));
+    }
+    assert!(catch_unwind(AssertUnwindSafe(|| map.clone())).is_err());
+    assert_eq!(1001, Rc::strong_count(&counter));
+    drop(map);
+    assert_eq!(1, Rc::strong_count(&counter));
+}
+
+#[test]
+fn standard_traits_work() {
+    type Map<K, V> = HashMap<K, V, ::fnv::FnvBuildHasher>;
+
+    let map = Map::from([(1, "a"), (2, "b"), (3, "c")]);
+    assert_eq!(3, map.len());
+    assert_eq!("b", map[&2]);
+    assert_eq!(map, [(3, "c"), (2, "b"), (1, "a")].iter().cloned().collect::<Map<_, _>>());
+    assert_eq!(r#"{1: "a"}"#, format!("{:?}", Map::from([(1, "a")])));
+
+    let mut extended = Map::default();
+    extended.extend(&map);
+    extended.extend(vec![(3, "d"), (4, "e")]);
+    assert_eq!(Map::from([(1, "a"), (2, "b"), (3, "d"), (4, "e")]), extended);
+
+    let mut strings = Map::<String, u32>::default();
+    strings.insert("x".to_string(), 1);
+    assert_eq!(1, strings["x"]);
+}
+
+#[test]
+#[should_panic(expected = "no entry found for key")]
+fn indexing_missing_key_panics() {
+    let map = HashMap::<u32, u32, ::fnv::FnvBuildHasher>::default();
+    let _ = map[&0];
+}
+
+#[test]
+fn clearing_works() {
+    use std::rc::Rc;
+    let max = 10000;
+    let counter = Rc::new(());
+    for mut map in HashMapBuilder::new(::fnv::FnvBuildHasher::default()).build_both() {
+        for n in 0..max {
+            map.insert(n, counter.clone());
+        }
+        let capacity = map.capacity;
+        map.clear();
+        assert_eq!(1, Rc::strong_count(&counter));
+        assert!(map.is_empty());
+        assert_eq!(capacity, map.capacity);
+        assert_eq!(None, map.iter().next());
+        for n in 0..max / 2 {
+            map.insert(n, counter.clone());
+        }
+        assert_eq!(max / 2, map.iter().count());
+        for n in 0..max {
+            assert_eq!(n < max / 2, map.get(&n).is_some());
+        }
+    }
+}
+
+#[test]
+fn dropping_works() {
+    use std::rc::Rc;
+    let counter = Rc::new(());
+    let mut map = HashMap::with_hasher(::fnv::FnvBuildHasher::default());
+    for n in 0..10000 {
+        map.insert(n, counter.clone());
+    }
+    drop(map);
+    assert_eq!(1, Rc::strong_count(&counter));
+}
+
+#[test]
+fn retaining_works() {
+    let max = 10000;
+    let mut map = HashMap::with_hasher(::fnv::FnvBuildHasher::default());
+    for n in 0..max {
         map.insert(n, n);
     }
-    for n in rng2.sample_iter(&Standard).take(max) {
-        let val = map.get(&n);
-        if Some(&n) != val {
-            map.debug();
-            panic!("Getting {} at {:?} failed. Was: {:?}", n, split_hash(map.hash(&n) as usize, map.capacity), val);
+    map.retain(|k, v| {
+        *v += 1;
+        k % 3 != 0
+    });
+    assert_eq!(max - (max + 2) / 3, map.len());
+    for n in 0..max {
+        if n % 3 == 0 {
+            assert!(map.get(&n).is_none());
+        } else {
+            assert_eq!(Some(&(n + 1)), map.get(&n));
         }
     }
 }
 
 #[test]
-fn removing_works() {
+fn extracting_works() {
     let max = 10000;
     let mut map = HashMap::with_hasher(::fnv::FnvBuildHasher::default());
     for n in 0..max {
         map.insert(n, n);
     }
+    let mut extracted = map.extract_if(|k, _| k % 2 == 0).take(max / 4).collect::<Vec<_>>();
+    assert_eq!(max - max / 4, map.len());
+    extracted.extend(map.extract_if(|k, _| k % 2 == 0));
+    extracted.sort();
+    assert_eq!((0..max).filter(|n| n % 2 == 0).map(|n| (n, n)).collect::<Vec<_>>(), extracted);
     for n in 0..max {
-        let val = map.remove(&n);
-        if Some((n, n)) != val {
-            map.debug();
-            panic!("Removing {} at {:?} failed. Was: {:?}", n, split_hash(map.hash(&n) as usize, map.capacity), val);
+        assert_eq!(n % 2 == 1, map.get(&n).is_some());
+        assert_eq!(n % 2 == 1, map.insert(n, n).is_some());
+    }
+    assert_eq!(max, map.len());
+}
+
+#[test]
+fn raw_entry_works() {
+    let max = 10000;
+    let mut map = HashMap::with_hasher(::fnv::FnvBuildHasher::default());
+    for n in 0..max {
+        let hash = map.hash(&n);
+        match map.raw_entry_mut().from_hash(hash, |k| *k == n) {
+            RawEntryMut::Occupied(_) => panic!("{} was already present", n),
+            RawEntryMut::Vacant(e) => {
+                let (k, v) = e.insert(n, n);
+                assert_eq!((n, n), (*k, *v));
+            },
         }
     }
     for n in 0..max {
-        assert!(map.get(&n).is_none());
+        let hash = map.hash(&n);
+        assert_eq!(Some((&n, &n)), map.raw_entry().from_hash(hash, |k| *k == n));
+        assert_eq!(None, map.raw_entry().from_hash(hash, |_| false));
+    }
+    for n in 0..max {
+        let hash = map.hash(&n);
+        match map.raw_entry_mut().from_hash(hash, |k| *k == n) {
+            RawEntryMut::Occupied(e) => assert_eq!((n, n), e.remove_entry()),
+            RawEntryMut::Vacant(_) => panic!("{} was missing", n),
+        }
     }
+    assert!(map.is_empty());
 }
 
 #[test]
-fn iterator_works() {
-    use std::collections::HashMap as HMap;
+fn wrong_raw_entry_hashes_are_safe() {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    // Relocating the chains the keys went into may panic, but has to leave the table intact.
+    let mut map = HashMap::with_hasher(::fnv::FnvBuildHasher::default());
+    let mut inserted = 0;
+    for n in 0..1000u32 {
+        let insert = || { map.raw_entry_mut().from_hash(n as u64 % 7, |k| *k == n).or_insert(n, n); };
+        if catch_unwind(AssertUnwindSafe(insert)).is_ok() {
+            inserted += 1;
+        }
+    }
+    for n in 1000..5000 {
+        if catch_unwind(AssertUnwindSafe(|| map.insert(n, n))).is_ok() {
+            inserted += 1;
+        }
+    }
+    assert!(inserted > 0);
+    assert_eq!(inserted, map.len());
+    assert_eq!(inserted, map.iter().count());
+    assert!(map.iter().all(|(k, v)| k == v));
+    assert!((1000..5000).all(|n| map.get(&n).map_or(true, |&v| v == n)));
+}
+
+#[test]
+fn reserving_works() {
     let max = 10000;
     let mut map = HashMap::with_hasher(::fnv::FnvBuildHasher::default());
-    let mut added = HMap::new();
+    map.try_reserve(max).unwrap();
+    let capacity = map.capacity;
     for n in 0..max {
-        map.insert(n, n);
-        added.insert(n, n);
+        assert_eq!(None, map.try_insert(n, n).unwrap());
     }
+    assert_eq!(capacity, map.capacity);
+    assert_eq!(Some((0, 0)), map.try_insert(0, 1).unwrap());
 
-    for (k, v) in &map {
-        assert_eq!(Some(v), added.remove(k).as_ref());
+    assert_eq!(Err(TryReserveError::CapacityOverflow), map.try_reserve(usize::MAX));
+    assert_eq!(Err(TryReserveError::CapacityOverflow), map.try_reserve(usize::MAX / 2));
+    assert!(HashMap::<u64, u64, _>::try_with_capacity(usize::MAX, ::fnv::FnvBuildHasher::default()).is_err());
+    assert_eq!(capacity, map.capacity);
+    for n in 0..max {
+        assert!(map.get(&n).is_some());
     }
-    assert!(added.is_empty());
-}
\ No newline at end of file
+}
+
+#[test]
+fn running_out_of_jumps_reports_allocation_failure() {
+    // Fails every allocation once armed.
+    #[derive(Default)]
+    struct Failing(::std::cell::Cell<bool>);
+
+    unsafe impl Allocator for Failing {
+        fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+            if self.0.get() {
+                return Err(AllocError);
+            }
+            Global.allocate(layout)
+        }
+
+        unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+            Global.deallocate(ptr, layout)
+        }
+    }
+
+    // Puts eight keys on every home slot, which leaves too few jumps once the slots wrap around.
+    #[derive(Default)]
+    struct Grouping(u64);
+
+    impl Hasher for Grouping {
+        fn finish(&self) -> u64 {
+            self.0 / 8 * 66
+        }
+
+        fn write(&mut self, _: &[u8]) {
+            unreachable!()
+        }
+
+        fn write_u32(&mut self, n: u32) {
+            self.0 = n as u64;
+        }
+    }
+
+    let alloc = Failing::default();
+    let mut map = HashMapBuilder::new_in(::core::hash::BuildHasherDefault::<Grouping>::default(), &alloc)
+        .max_jump_index(2)
+        .build();
+    map.try_reserve(1000).unwrap();
+    alloc.0.set(true);
+    let mut inserted = 0u32;
+    let failed = loop {
+        match map.try_insert(inserted, inserted) {
+            Ok(None) => inserted += 1,
+            Ok(Some(_)) => unreachable!(),
+            Err(error) => break error,
+        }
+    };
+    assert!(inserted < 1000);
+    assert!(matches!(failed.error, TryReserveError::AllocError { .. }));
+    assert_eq!((inserted, inserted), (failed.key, failed.value));
+    assert_eq!(inserted as usize, map.len());
+    assert_eq!(inserted as usize, map.iter().count());
+    for n in 0..inserted {
+        assert_eq!(Some(&n), map.get(&n));
+    }
+    assert!(map.get(&inserted).is_none());
+
+    alloc.0.set(false);
+    for n in inserted..1000 {
+        assert_eq!(None, map.try_insert(n, n).unwrap());
+    }
+    assert!((0..1000).all(|n| map.get(&n) == Some(&n)));
+}
+
+#[test]
+fn custom_allocator_works() {
+    #[derive(Default)]
+    struct Counting {
+        allocated: ::std::cell::Cell<usize>,
+        allocations: ::std::cell::Cell<usize>
+    }
+
+    unsafe impl Allocator for Counting {
+        fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+            self.allocated.set(self.allocated.get() + layout.size());
+            self.allocations.set(self.allocations.get() + 1);
+            Global.allocate(layout)
+        }
+
+        unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+            self.allocated.set(self.allocated.get() - layout.size());
+            Global.deallocate(ptr, layout)
+        }
+    }
+
+    let max = 10000;
+    let alloc = Counting::default();
+    {
+        let mut map = HashMap::with_hasher_in(::fnv::FnvBuildHasher::default(), &alloc);
+        for n in 0..max {
+            map.insert(n, n);
+        }
+        assert!(alloc.allocations.get() > 1);
+        assert_eq!(map.capacity * mem::size_of::<Cell<i32, i32>>(), alloc.allocated.get());
+        map.drain();
+        for n in 0..max {
+            assert!(map.get(&n).is_none());
+        }
+    }
+    assert_eq!(0, alloc.allocated.get());
+}
+
+// Gives keys only 64 home slots, so that chains are often relocated. Shared by the tests and
+// the fuzz target.
+#[cfg(any(test, feature = "debug-invariants"))]
+#[doc(hidden)]
+#[derive(Clone, Default)]
+pub struct Colliding(u64);
+
+#[cfg(any(test, feature = "debug-invariants"))]
+impl Hasher for Colliding {
+    fn finish(&self) -> u64 {
+        self.0 & 0x3f
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 = self.0.wrapping_mul(31).wrapping_add(b as u64);
+        }
+    }
+}
+
+#[cfg(any(test, feature = "debug-invariants"))]
+#[doc(hidden)]
+pub type BuildColliding = core::hash::BuildHasherDefault<Colliding>;
+
+// Makes the `Bomb`s sharing it panic after a number of hashes, comparisons or drops.
+#[cfg(test)]
+#[derive(Default)]
+struct Fuse {
+    hashes: ::std::cell::Cell<Option<usize>>,
+    comparisons: ::std::cell::Cell<Option<usize>>,
+    drops: ::std::cell::Cell<Option<usize>>,
+    dropped: ::std::cell::Cell<usize>
+}
+
+#[cfg(test)]
+impl Fuse {
+    fn burn(left: &::std::cell::Cell<Option<usize>>) {
+        match left.get() {
+            Some(0) => {
+                left.set(None);
+                panic!("fuse burned out");
+            },
+            Some(n) => left.set(Some(n - 1)),
+            None => {},
+        }
+    }
+}
+
+#[cfg(test)]
+#[derive(Clone)]
+struct Bomb<'a>(u32, &'a Fuse);
+
+#[cfg(test)]
+impl<'a> Hash for Bomb<'a> {
+    fn hash<S: Hasher>(&self, state: &mut S) {
+        Fuse::burn(&self.1.hashes);
+        self.0.hash(state);
+    }
+}
+
+#[cfg(test)]
+impl<'a> PartialEq for Bomb<'a> {
+    fn eq(&self, other: &Self) -> bool {
+        Fuse::burn(&self.1.comparisons);
+        self.0 == other.0
+    }
+}
+
+#[cfg(test)]
+impl<'a> Eq for Bomb<'a> {}
+
+#[cfg(test)]
+impl<'a> Drop for Bomb<'a> {
+    fn drop(&mut self) {
+        self.1.dropped.set(self.1.dropped.get() + 1);
+        Fuse::burn(&self.1.drops);
+    }
+}
+
+#[cfg(test)]
+fn assert_intact<'a, H: BuildHasher>(map: &HashMap<Bomb<'a>, u32, H>) {
+    assert_eq!(map.len(), map.iter().count());
+    for (k, v) in map {
+        assert_eq!(k.0, *v);
+        assert_eq!(Some(v), map.get(k));
+    }
+}
+
+#[test]
+fn panicking_hash_is_safe() {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    for &(incremental, max_jump) in &[(false, 125), (true, 125), (false, 3), (true, 3)] {
+        let fuse = Fuse::default();
+        let mut created = 0;
+        {
+            let builder = HashMapBuilder::new(::fnv::FnvBuildHasher::default()).max_jump_index(max_jump);
+            let builder = if incremental { builder.incremental_resize(1) } else { builder };
+            let mut map = builder.build();
+            let mut panics = 0;
+            for n in 0..5000 {
+                // Arm the fuse right before growing, so that rehashing is interrupted midway
+                if map.len() + 1 >= map.capacity() || n % 97 == 0 {
+                    fuse.hashes.set(Some(n as usize % 13));
+                }
+                created += 1;
+                if catch_unwind(AssertUnwindSafe(|| map.insert(Bomb(n, &fuse), n))).is_err() {
+                    panics += 1;
+                }
+                fuse.hashes.set(None);
+                if n % 250 == 0 {
+                    assert_intact(&map);
+                }
+            }
+            assert!(panics > 0);
+            assert_intact(&map);
+            for n in 0..5000 {
+                created += 1;
+                if map.get(&Bomb(n, &fuse)).is_none() {
+                    created += 1;
+                    map.insert(Bomb(n, &fuse), n);
+                }
+            }
+            assert_eq!(5000, map.len());
+            assert_intact(&map);
+        }
+        // Every key was dropped exactly once
+        assert_eq!(created, fuse.dropped.get());
+    }
+}
+
+#[test]
+fn panicking_comparison_is_safe() {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    let fuse = Fuse::default();
+    let mut map = HashMap::with_hasher(::fnv::FnvBuildHasher::default());
+    for n in 0..1000 {
+        map.insert(Bomb(n, &fuse), n);
+    }
+    for n in 0..1000 {
+        fuse.comparisons.set(Some(0));
+        assert!(catch_unwind(AssertUnwindSafe(|| map.insert(Bomb(n, &fuse), n))).is_err());
+        fuse.comparisons.set(Some(0));
+        assert!(catch_unwind(AssertUnwindSafe(|| map.remove(&Bomb(n, &fuse)))).is_err());
+        fuse.comparisons.set(Some(0));
+        assert!(catch_unwind(AssertUnwindSafe(|| { map.entry(Bomb(n, &fuse)); })).is_err());
+    }
+    fuse.comparisons.set(None);
+    assert_eq!(1000, map.len());
+    assert_intact(&map);
+}
+
+#[test]
+fn panicking_drop_is_safe() {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    let fuse = Fuse::default();
+    let mut map = HashMap::with_hasher(::fnv::FnvBuildHasher::default());
+    for n in 0..1000 {
+        map.insert(Bomb(n, &fuse), n);
+    }
+    fuse.drops.set(Some(10));
+    assert!(catch_unwind(AssertUnwindSafe(|| { map.drain(); })).is_err());
+    assert_eq!(1000, fuse.dropped.get());
+    assert!(map.is_empty());
+    for n in 0..1000 {
+        map.insert(Bomb(n, &fuse), n);
+    }
+    fuse.drops.set(Some(10));
+    assert!(catch_unwind(AssertUnwindSafe(|| map.retain(|k, _| k.0 % 2 == 0))).is_err());
+    assert_intact(&map);
+    fuse.drops.set(None);
+    map.retain(|k, _| k.0 % 2 == 0);
+    assert_eq!(500, map.len());
+    assert_intact(&map);
+
+    // Chains running across cells must not be left half cleared.
+    fuse.drops.set(Some(10));
+    assert!(catch_unwind(AssertUnwindSafe(|| map.clear())).is_err());
+    assert!(map.is_empty());
+    for n in 0..1000 {
+        map.insert(Bomb(n, &fuse), n);
+    }
+    assert_eq!(1000, map.len());
+    assert_intact(&map);
+}
+
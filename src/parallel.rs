@@ -0,0 +1,331 @@
+use core::hash::{BuildHasher, Hash};
+use core::marker::PhantomData;
+use core::ptr;
+
+use rayon::iter::plumbing::{bridge_unindexed, Folder, UnindexedConsumer, UnindexedProducer};
+use rayon::iter::{FromParallelIterator, IntoParallelIterator, ParallelExtend, ParallelIterator};
+
+use simd::BitMask;
+use {Allocator, Bucket, Cell, Global, HashMap};
+
+// Splits the cell array into ranges of cells that are visited on different threads.
+// Cells still being migrated by an incremental resize come after the current ones.
+// Whether sending the produced items is sound is decided by the public iterator types.
+struct CellProducer<'a, K: 'a, V: 'a, T> {
+    ptr: *mut Cell<K, V>,
+    capacity: usize,
+    old_ptr: *mut Cell<K, V>,
+    start: usize,
+    end: usize,
+    take: unsafe fn(*mut Cell<K, V>, usize) -> T,
+    marker: PhantomData<&'a ()>
+}
+
+unsafe impl<'a, K: 'a, V: 'a, T> Send for CellProducer<'a, K, V, T> {}
+
+impl<'a, K: 'a, V: 'a, T> CellProducer<'a, K, V, T> {
+    fn new<H, A: Allocator>(map: &'a HashMap<K, V, H, A>, take: unsafe fn(*mut Cell<K, V>, usize) -> T) -> Self {
+        CellProducer {
+            ptr: map.ptr,
+            capacity: map.capacity,
+            old_ptr: map.old.as_ref().map_or(ptr::null_mut(), |old| old.ptr),
+            start: 0,
+            end: map.cell_count(),
+            take,
+            marker: PhantomData
+        }
+    }
+
+    unsafe fn cell_ptr(&self, cell: usize) -> *mut Cell<K, V> {
+        if cell < self.capacity {
+            self.ptr.add(cell)
+        } else {
+            self.old_ptr.add(cell - self.capacity)
+        }
+    }
+}
+
+impl<'a, K: 'a, V: 'a, T> UnindexedProducer for CellProducer<'a, K, V, T> {
+    type Item = T;
+
+    fn split(self) -> (Self, Option<Self>) {
+        if self.end - self.start <= 1 {
+            return (self, None);
+        }
+        let mid = self.start + (self.end - self.start) / 2;
+        let right = CellProducer {
+            ptr: self.ptr,
+            capacity: self.capacity,
+            old_ptr: self.old_ptr,
+            start: mid,
+            end: self.end,
+            take: self.take,
+            marker: PhantomData
+        };
+        (CellProducer { end: mid, ..self }, Some(right))
+    }
+
+    fn fold_with<F: Folder<T>>(self, mut folder: F) -> F {
+        unsafe {
+            for cell in self.start..self.end {
+                let cur_cell = self.cell_ptr(cell);
+                for slot in BitMask((*cur_cell).meta.full_mask()) {
+                    folder = folder.consume((self.take)(cur_cell, slot));
+                    if folder.full() {
+                        return folder;
+                    }
+                }
+            }
+        }
+        folder
+    }
+}
+
+// Buckets are laid out in order at the start of the cell data.
+unsafe fn bucket_ptr<K, V>(cell: *mut Cell<K, V>, slot: usize) -> *mut Bucket<K, V> {
+    (ptr::addr_of_mut!((*cell).data) as *mut Bucket<K, V>).add(slot)
+}
+
+unsafe fn take_ref<'a, K: 'a, V: 'a>(cell: *mut Cell<K, V>, slot: usize) -> (&'a K, &'a V) {
+    let bucket = &*bucket_ptr(cell, slot);
+    (&bucket.key, &bucket.value)
+}
+
+unsafe fn take_mut<'a, K: 'a, V: 'a>(cell: *mut Cell<K, V>, slot: usize) -> (&'a K, &'a mut V) {
+    let bucket = &mut *bucket_ptr(cell, slot);
+    (&bucket.key, &mut bucket.value)
+}
+
+// Slots are emptied as they are taken, so the map only drops what was not consumed.
+unsafe fn take_owned<K, V>(cell: *mut Cell<K, V>, slot: usize) -> (K, V) {
+    (*cell).meta.0[slot].set_empty();
+    let bucket = ptr::read(bucket_ptr(cell, slot));
+    (bucket.key, bucket.value)
+}
+
+pub struct ParIter<'a, K: 'a, V: 'a, H: 'a, A: Allocator + 'a = Global>(&'a HashMap<K, V, H, A>);
+
+impl<'a, K: 'a, V: 'a, H: 'a, A: Allocator + 'a> ParallelIterator for ParIter<'a, K, V, H, A>
+    where K: Sync,
+          V: Sync,
+          H: Sync,
+          A: Sync
+{
+    type Item = (&'a K, &'a V);
+
+    fn drive_unindexed<C: UnindexedConsumer<Self::Item>>(self, consumer: C) -> C::Result {
+        let producer = CellProducer::new(self.0, take_ref);
+        bridge_unindexed(producer, consumer)
+    }
+}
+
+pub struct ParIterMut<'a, K: 'a, V: 'a, H: 'a, A: Allocator + 'a = Global>(&'a mut HashMap<K, V, H, A>);
+
+impl<'a, K: 'a, V: 'a, H: 'a, A: Allocator + 'a> ParallelIterator for ParIterMut<'a, K, V, H, A>
+    where K: Send + Sync,
+          V: Send,
+          H: Send,
+          A: Send
+{
+    type Item = (&'a K, &'a mut V);
+
+    fn drive_unindexed<C: UnindexedConsumer<Self::Item>>(self, consumer: C) -> C::Result {
+        let producer = CellProducer::new(self.0, take_mut);
+        bridge_unindexed(producer, consumer)
+    }
+}
+
+pub struct IntoParIter<K, V, H, A: Allocator = Global>(HashMap<K, V, H, A>);
+
+impl<K, V, H, A: Allocator> ParallelIterator for IntoParIter<K, V, H, A>
+    where K: Send,
+          V: Send,
+          H: Send,
+          A: Send
+{
+    type Item = (K, V);
+
+    fn drive_unindexed<C: UnindexedConsumer<Self::Item>>(self, consumer: C) -> C::Result {
+        let producer = CellProducer::new(&self.0, take_owned);
+        bridge_unindexed(producer, consumer)
+    }
+}
+
+pub struct ParKeys<'a, K: 'a, V: 'a, H: 'a, A: Allocator + 'a = Global>(ParIter<'a, K, V, H, A>);
+
+impl<'a, K: 'a, V: 'a, H: 'a, A: Allocator + 'a> ParallelIterator for ParKeys<'a, K, V, H, A>
+    where K: Sync,
+          V: Sync,
+          H: Sync,
+          A: Sync
+{
+    type Item = &'a K;
+
+    fn drive_unindexed<C: UnindexedConsumer<Self::Item>>(self, consumer: C) -> C::Result {
+        self.0.map(|(k, _)| k).drive_unindexed(consumer)
+    }
+}
+
+pub struct ParValues<'a, K: 'a, V: 'a, H: 'a, A: Allocator + 'a = Global>(ParIter<'a, K, V, H, A>);
+
+impl<'a, K: 'a, V: 'a, H: 'a, A: Allocator + 'a> ParallelIterator for ParValues<'a, K, V, H, A>
+    where K: Sync,
+          V: Sync,
+          H: Sync,
+          A: Sync
+{
+    type Item = &'a V;
+
+    fn drive_unindexed<C: UnindexedConsumer<Self::Item>>(self, consumer: C) -> C::Result {
+        self.0.map(|(_, v)| v).drive_unindexed(consumer)
+    }
+}
+
+impl<K, V, H, A> HashMap<K, V, H, A>
+    where K: Hash + PartialEq + Sync,
+          V: Sync,
+          H: BuildHasher + Sync,
+          A: Allocator + Sync
+{
+    pub fn par_keys(&self) -> ParKeys<'_, K, V, H, A> {
+        ParKeys(ParIter(self))
+    }
+
+    pub fn par_values(&self) -> ParValues<'_, K, V, H, A> {
+        ParValues(ParIter(self))
+    }
+}
+
+impl<'a, K: 'a, V: 'a, H: 'a, A: Allocator + 'a> IntoParallelIterator for &'a HashMap<K, V, H, A>
+    where K: Sync,
+          V: Sync,
+          H: Sync,
+          A: Sync
+{
+    type Iter = ParIter<'a, K, V, H, A>;
+    type Item = (&'a K, &'a V);
+
+    fn into_par_iter(self) -> Self::Iter {
+        ParIter(self)
+    }
+}
+
+impl<'a, K: 'a, V: 'a, H: 'a, A: Allocator + 'a> IntoParallelIterator for &'a mut HashMap<K, V, H, A>
+    where K: Send + Sync,
+          V: Send,
+          H: Send,
+          A: Send
+{
+    type Iter = ParIterMut<'a, K, V, H, A>;
+    type Item = (&'a K, &'a mut V);
+
+    fn into_par_iter(self) -> Self::Iter {
+        ParIterMut(self)
+    }
+}
+
+impl<K, V, H, A: Allocator> IntoParallelIterator for HashMap<K, V, H, A>
+    where K: Send,
+          V: Send,
+          H: Send,
+          A: Send
+{
+    type Iter = IntoParIter<K, V, H, A>;
+    type Item = (K, V);
+
+    fn into_par_iter(self) -> Self::Iter {
+        IntoParIter(self)
+    }
+}
+
+// Later entries win when the same key is present in both maps.
+fn merge<K, V, H, A>(mut left: HashMap<K, V, H, A>, mut right: HashMap<K, V, H, A>) -> HashMap<K, V, H, A>
+    where K: Hash + PartialEq,
+          H: BuildHasher,
+          A: Allocator
+{
+    if left.len() >= right.len() {
+        for (k, v) in right {
+            left.insert(k, v);
+        }
+        left
+    } else {
+        for (k, v) in left {
+            right.entry(k).or_insert(v);
+        }
+        right
+    }
+}
+
+fn collect_per_thread<I, K, V, H, A>(iter: I) -> HashMap<K, V, H, A>
+    where I: IntoParallelIterator<Item = (K, V)>,
+          K: Hash + PartialEq + Send,
+          V: Send,
+          H: BuildHasher + Default + Send,
+          A: Allocator + Default + Send
+{
+    iter.into_par_iter()
+        .fold(HashMap::default, |mut map, (k, v)| {
+            map.insert(k, v);
+            map
+        })
+        .reduce(HashMap::default, merge)
+}
+
+impl<K, V, H, A> FromParallelIterator<(K, V)> for HashMap<K, V, H, A>
+    where K: Hash + PartialEq + Send,
+          V: Send,
+          H: BuildHasher + Default + Send,
+          A: Allocator + Default + Send
+{
+    fn from_par_iter<I: IntoParallelIterator<Item = (K, V)>>(iter: I) -> Self {
+        collect_per_thread(iter)
+    }
+}
+
+impl<K, V, H, A> ParallelExtend<(K, V)> for HashMap<K, V, H, A>
+    where K: Hash + PartialEq + Send,
+          V: Send,
+          H: BuildHasher + Default + Send,
+          A: Allocator + Default + Send
+{
+    fn par_extend<I: IntoParallelIterator<Item = (K, V)>>(&mut self, iter: I) {
+        let collected: HashMap<K, V, H, A> = collect_per_thread(iter);
+        self.reserve(collected.len());
+        for (k, v) in collected {
+            self.insert(k, v);
+        }
+    }
+}
+
+#[test]
+fn parallel_iterators_work() {
+    use rayon::iter::{IntoParallelRefIterator, IntoParallelRefMutIterator};
+    let max = 100000u64;
+    let mut map = HashMap::with_hasher(::fnv::FnvBuildHasher::default());
+    for n in 0..max {
+        map.insert(n, n);
+    }
+    let sum = max * (max - 1) / 2;
+    assert_eq!(sum, map.par_iter().map(|(k, v)| k + v).sum::<u64>() / 2);
+    assert_eq!(sum, map.par_keys().sum::<u64>());
+    map.par_iter_mut().for_each(|(_, v)| *v += 1);
+    assert_eq!(sum + max, map.par_values().sum::<u64>());
+    assert_eq!(Some(&(max / 2 + 1)), map.par_iter().find_any(|&(k, _)| *k == max / 2).map(|(_, v)| v));
+    assert_eq!(sum + max, map.into_par_iter().map(|(_, v)| v).sum::<u64>());
+}
+
+#[test]
+fn parallel_collecting_works() {
+    let max = 100000u64;
+    let mut map: HashMap<u64, u64, ::fnv::FnvBuildHasher> = (0..max).into_par_iter().map(|n| (n % 1000, n)).collect();
+    assert_eq!(1000, map.len());
+    for n in 0..1000 {
+        assert_eq!(Some(&(max - 1000 + n)), map.get(&n));
+    }
+    map.par_extend((0..max).into_par_iter().map(|n| (n, n)));
+    assert_eq!(max as usize, map.len());
+    for n in 0..max {
+        assert_eq!(Some(&n), map.get(&n));
+    }
+}
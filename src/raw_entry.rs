@@ -0,0 +1,230 @@
+use core::mem;
+use core::hash::{BuildHasher, Hash};
+
+use {Allocator, Bucket, Equivalent, Global, HashMap, Vacancy};
+
+// The hashes given to these builders should be the ones the map's hasher produces for the keys,
+// since buckets are moved around by rehashing them on relocation and growth. A wrong hash makes
+// lookups miss the key, and inserting with one can make later insertions panic once the chain it
+// went into has to be relocated. The table itself stays intact either way.
+
+pub struct RawEntryBuilder<'a, K: 'a, V: 'a, H: 'a, A: Allocator + 'a = Global> {
+    map: &'a HashMap<K, V, H, A>,
+}
+
+impl<'a, K: 'a, V: 'a, H: 'a, A: Allocator + 'a> RawEntryBuilder<'a, K, V, H, A>
+    where K: Hash + PartialEq,
+          H: BuildHasher
+{
+    pub(crate) fn new(map: &'a HashMap<K, V, H, A>) -> Self {
+        RawEntryBuilder {
+            map
+        }
+    }
+
+    pub fn from_key<Q>(self, key: &Q) -> Option<(&'a K, &'a V)>
+        where Q: ?Sized + Hash + Equivalent<K>
+    {
+        let hash = self.map.hash(key); // NOTE: Possible panic
+        self.from_key_hashed_nocheck(hash, key)
+    }
+
+    pub fn from_key_hashed_nocheck<Q>(self, hash: u64, key: &Q) -> Option<(&'a K, &'a V)>
+        where Q: ?Sized + Equivalent<K>
+    {
+        self.from_hash(hash, |k| key.equivalent(k))
+    }
+
+    pub fn from_hash<F>(self, hash: u64, is_match: F) -> Option<(&'a K, &'a V)>
+        where F: FnMut(&K) -> bool
+    {
+        unsafe {
+            self.map.find(hash as usize, is_match).map(|bucket| (&(*bucket).key, &(*bucket).value)) // NOTE: Possible panic
+        }
+    }
+}
+
+pub struct RawEntryBuilderMut<'a, K: 'a, V: 'a, H: 'a, A: Allocator + 'a = Global> {
+    map: &'a mut HashMap<K, V, H, A>,
+}
+
+impl<'a, K: 'a, V: 'a, H: 'a, A: Allocator + 'a> RawEntryBuilderMut<'a, K, V, H, A>
+    where K: Hash + PartialEq,
+          H: BuildHasher
+{
+    pub(crate) fn new(map: &'a mut HashMap<K, V, H, A>) -> Self {
+        RawEntryBuilderMut {
+            map
+        }
+    }
+
+    pub fn from_key<Q>(self, key: &Q) -> RawEntryMut<'a, K, V, H, A>
+        where Q: ?Sized + Hash + Equivalent<K>
+    {
+        let hash = self.map.hash(key); // NOTE: Possible panic
+        self.from_key_hashed_nocheck(hash, key)
+    }
+
+    pub fn from_key_hashed_nocheck<Q>(self, hash: u64, key: &Q) -> RawEntryMut<'a, K, V, H, A>
+        where Q: ?Sized + Equivalent<K>
+    {
+        self.from_hash(hash, |k| key.equivalent(k))
+    }
+
+    pub fn from_hash<F>(self, hash: u64, is_match: F) -> RawEntryMut<'a, K, V, H, A>
+        where F: FnMut(&K) -> bool
+    {
+        self.map.grow_if_needed();
+        let hash = hash as usize;
+        unsafe {
+            match self.map.probe_mut(hash, is_match) { // NOTE: Possible panic
+                Ok((prev, pos)) => RawEntryMut::Occupied(RawOccupiedEntryMut {
+                    map: self.map,
+                    prev,
+                    pos
+                }),
+                Err(vacancy) => RawEntryMut::Vacant(RawVacantEntryMut {
+                    map: self.map,
+                    hash,
+                    vacancy
+                }),
+            }
+        }
+    }
+}
+
+pub enum RawEntryMut<'a, K: 'a, V: 'a, H: 'a, A: Allocator + 'a = Global> {
+    Occupied(RawOccupiedEntryMut<'a, K, V, H, A>),
+    Vacant(RawVacantEntryMut<'a, K, V, H, A>),
+}
+
+impl<'a, K: 'a, V: 'a, H: 'a, A: Allocator + 'a> RawEntryMut<'a, K, V, H, A>
+    where K: Hash + PartialEq,
+          H: BuildHasher
+{
+    pub fn or_insert(self, default_key: K, default_value: V) -> (&'a mut K, &'a mut V) {
+        match self {
+            RawEntryMut::Occupied(entry) => entry.into_key_value(),
+            RawEntryMut::Vacant(entry) => entry.insert(default_key, default_value),
+        }
+    }
+
+    pub fn or_insert_with<F>(self, default: F) -> (&'a mut K, &'a mut V)
+        where F: FnOnce() -> (K, V)
+    {
+        match self {
+            RawEntryMut::Occupied(entry) => entry.into_key_value(),
+            RawEntryMut::Vacant(entry) => {
+                let (key, value) = default();
+                entry.insert(key, value)
+            },
+        }
+    }
+
+    pub fn and_modify<F>(self, f: F) -> Self
+        where F: FnOnce(&mut K, &mut V)
+    {
+        match self {
+            RawEntryMut::Occupied(mut entry) => {
+                {
+                    let (key, value) = entry.get_key_value_mut();
+                    f(key, value);
+                }
+                RawEntryMut::Occupied(entry)
+            },
+            RawEntryMut::Vacant(entry) => RawEntryMut::Vacant(entry),
+        }
+    }
+}
+
+pub struct RawOccupiedEntryMut<'a, K: 'a, V: 'a, H: 'a, A: Allocator + 'a = Global> {
+    map: &'a mut HashMap<K, V, H, A>,
+    prev: usize,
+    pos: usize,
+}
+
+impl<'a, K: 'a, V: 'a, H: 'a, A: Allocator + 'a> RawOccupiedEntryMut<'a, K, V, H, A>
+    where K: Hash + PartialEq,
+          H: BuildHasher
+{
+    pub fn key(&self) -> &K {
+        unsafe { &(*self.map.bucket_ptr(self.pos)).key }
+    }
+
+    // Changing the key so that it hashes or compares differently corrupts the map.
+    pub fn key_mut(&mut self) -> &mut K {
+        unsafe { &mut (*self.map.bucket_ptr(self.pos)).key }
+    }
+
+    pub fn into_key(self) -> &'a mut K {
+        unsafe { &mut (*self.map.bucket_ptr(self.pos)).key }
+    }
+
+    pub fn get(&self) -> &V {
+        unsafe { &(*self.map.bucket_ptr(self.pos)).value }
+    }
+
+    pub fn get_mut(&mut self) -> &mut V {
+        unsafe { &mut (*self.map.bucket_ptr(self.pos)).value }
+    }
+
+    pub fn into_mut(self) -> &'a mut V {
+        unsafe { &mut (*self.map.bucket_ptr(self.pos)).value }
+    }
+
+    pub fn get_key_value(&self) -> (&K, &V) {
+        unsafe {
+            let bucket = &*self.map.bucket_ptr(self.pos);
+            (&bucket.key, &bucket.value)
+        }
+    }
+
+    pub fn get_key_value_mut(&mut self) -> (&mut K, &mut V) {
+        unsafe {
+            let bucket = &mut *self.map.bucket_ptr(self.pos);
+            (&mut bucket.key, &mut bucket.value)
+        }
+    }
+
+    pub fn into_key_value(self) -> (&'a mut K, &'a mut V) {
+        unsafe {
+            let bucket = &mut *self.map.bucket_ptr(self.pos);
+            (&mut bucket.key, &mut bucket.value)
+        }
+    }
+
+    pub fn insert(&mut self, value: V) -> V {
+        mem::replace(self.get_mut(), value)
+    }
+
+    pub fn insert_key(&mut self, key: K) -> K {
+        mem::replace(self.key_mut(), key)
+    }
+
+    pub fn remove(self) -> V {
+        self.remove_entry().1
+    }
+
+    pub fn remove_entry(self) -> (K, V) {
+        let bucket = unsafe { self.map.remove_at(self.prev, self.pos) };
+        (bucket.key, bucket.value)
+    }
+}
+
+pub struct RawVacantEntryMut<'a, K: 'a, V: 'a, H: 'a, A: Allocator + 'a = Global> {
+    map: &'a mut HashMap<K, V, H, A>,
+    hash: usize,
+    vacancy: Vacancy,
+}
+
+impl<'a, K: 'a, V: 'a, H: 'a, A: Allocator + 'a> RawVacantEntryMut<'a, K, V, H, A>
+    where K: Hash + PartialEq,
+          H: BuildHasher
+{
+    pub fn insert(self, key: K, value: V) -> (&'a mut K, &'a mut V) {
+        unsafe {
+            let bucket = &mut *self.map.insert_vacant(self.hash, self.vacancy, Bucket::new(key, value));
+            (&mut bucket.key, &mut bucket.value)
+        }
+    }
+}
@@ -0,0 +1,200 @@
+use alloc::vec::Vec;
+use core::cmp;
+use core::fmt;
+use core::hash::{BuildHasher, Hash};
+use core::marker::PhantomData;
+
+use serde::de::{Deserialize, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde::ser::{Serialize, SerializeMap, SerializeSeq, Serializer};
+
+use {Allocator, HashMap, HashSet};
+
+// Upper bound for trusting size hints of untrusted input when pre-sizing
+const MAX_PREALLOCATION: usize = 1 << 12;
+
+impl<K, V, H, A> Serialize for HashMap<K, V, H, A>
+    where K: Serialize + Hash + PartialEq,
+          V: Serialize,
+          H: BuildHasher,
+          A: Allocator
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.len()))?;
+        for (k, v) in self {
+            map.serialize_entry(k, v)?;
+        }
+        map.end()
+    }
+}
+
+impl<T, H, A> Serialize for HashSet<T, H, A>
+    where T: Serialize + Hash + Eq,
+          H: BuildHasher,
+          A: Allocator
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(self.len()))?;
+        for v in self {
+            seq.serialize_element(v)?;
+        }
+        seq.end()
+    }
+}
+
+// Serializes the wrapped map or set in ascending key order, so that the output does not depend
+// on the hasher or insertion history.
+pub struct Sorted<'a, T: 'a>(pub &'a T);
+
+impl<'a, K, V, H, A> Serialize for Sorted<'a, HashMap<K, V, H, A>>
+    where K: Serialize + Hash + PartialEq + Ord,
+          V: Serialize,
+          H: BuildHasher,
+          A: Allocator
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut entries = self.0.iter().collect::<Vec<_>>();
+        entries.sort_by(|a, b| a.0.cmp(b.0));
+        let mut map = serializer.serialize_map(Some(entries.len()))?;
+        for (k, v) in entries {
+            map.serialize_entry(k, v)?;
+        }
+        map.end()
+    }
+}
+
+impl<'a, T, H, A> Serialize for Sorted<'a, HashSet<T, H, A>>
+    where T: Serialize + Hash + Eq + Ord,
+          H: BuildHasher,
+          A: Allocator
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut values = self.0.iter().collect::<Vec<_>>();
+        values.sort();
+        let mut seq = serializer.serialize_seq(Some(values.len()))?;
+        for v in values {
+            seq.serialize_element(v)?;
+        }
+        seq.end()
+    }
+}
+
+impl<'de, K, V, H, A> Deserialize<'de> for HashMap<K, V, H, A>
+    where K: Deserialize<'de> + Hash + PartialEq,
+          V: Deserialize<'de>,
+          H: BuildHasher + Default,
+          A: Allocator + Default
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct MapVisitor<K, V, H, A: Allocator>(PhantomData<HashMap<K, V, H, A>>);
+
+        impl<'de, K, V, H, A> Visitor<'de> for MapVisitor<K, V, H, A>
+            where K: Deserialize<'de> + Hash + PartialEq,
+                  V: Deserialize<'de>,
+                  H: BuildHasher + Default,
+                  A: Allocator + Default
+        {
+            type Value = HashMap<K, V, H, A>;
+
+            fn expecting(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+                fmt.write_str("a map")
+            }
+
+            fn visit_map<M: MapAccess<'de>>(self, mut access: M) -> Result<Self::Value, M::Error> {
+                let capacity = cmp::min(access.size_hint().unwrap_or(0), MAX_PREALLOCATION);
+                let mut map = HashMap::with_capacity_in(capacity, H::default(), A::default());
+                while let Some((key, value)) = access.next_entry()? {
+                    map.insert(key, value);
+                }
+                Ok(map)
+            }
+        }
+
+        deserializer.deserialize_map(MapVisitor(PhantomData))
+    }
+}
+
+impl<'de, T, H, A> Deserialize<'de> for HashSet<T, H, A>
+    where T: Deserialize<'de> + Hash + Eq,
+          H: BuildHasher + Default,
+          A: Allocator + Default
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct SetVisitor<T, H, A: Allocator>(PhantomData<HashSet<T, H, A>>);
+
+        impl<'de, T, H, A> Visitor<'de> for SetVisitor<T, H, A>
+            where T: Deserialize<'de> + Hash + Eq,
+                  H: BuildHasher + Default,
+                  A: Allocator + Default
+        {
+            type Value = HashSet<T, H, A>;
+
+            fn expecting(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+                fmt.write_str("a sequence")
+            }
+
+            fn visit_seq<S: SeqAccess<'de>>(self, mut access: S) -> Result<Self::Value, S::Error> {
+                let capacity = cmp::min(access.size_hint().unwrap_or(0), MAX_PREALLOCATION);
+                let mut set = HashSet::with_capacity_in(capacity, H::default(), A::default());
+                while let Some(value) = access.next_element()? {
+                    set.insert(value);
+                }
+                Ok(set)
+            }
+        }
+
+        deserializer.deserialize_seq(SetVisitor(PhantomData))
+    }
+}
+
+#[test]
+fn serde_round_trip_works() {
+    let max = 1000;
+    let mut map = HashMap::<u32, u32, ::fnv::FnvBuildHasher>::default();
+    let mut set = HashSet::<u32, ::fnv::FnvBuildHasher>::default();
+    for n in 0..max {
+        map.insert(n, 2 * n);
+        set.insert(n);
+    }
+
+    let json = ::serde_json::to_string(&map).unwrap();
+    let map = ::serde_json::from_str::<HashMap<u32, u32, ::fnv::FnvBuildHasher>>(&json).unwrap();
+    assert_eq!(max as usize, map.len());
+    for n in 0..max {
+        assert_eq!(Some(&(2 * n)), map.get(&n));
+    }
+
+    let json = ::serde_json::to_string(&set).unwrap();
+    let deserialized = ::serde_json::from_str::<HashSet<u32, ::fnv::FnvBuildHasher>>(&json).unwrap();
+    assert_eq!(set, deserialized);
+}
+
+#[test]
+fn length_prefixed_round_trip_works() {
+    let max = 1000;
+    let mut map = HashMap::<u32, u32, ::fnv::FnvBuildHasher>::default();
+    let mut set = HashSet::<u32, ::fnv::FnvBuildHasher>::default();
+    for n in 0..max {
+        map.insert(n, 2 * n);
+        set.insert(n);
+    }
+
+    let bytes = ::bincode::serialize(&map).unwrap();
+    let deserialized = ::bincode::deserialize::<HashMap<u32, u32, ::fnv::FnvBuildHasher>>(&bytes).unwrap();
+    assert_eq!(map, deserialized);
+
+    let bytes = ::bincode::serialize(&set).unwrap();
+    let deserialized = ::bincode::deserialize::<HashSet<u32, ::fnv::FnvBuildHasher>>(&bytes).unwrap();
+    assert_eq!(set, deserialized);
+}
+
+#[test]
+fn sorted_serialization_works() {
+    let mut map = HashMap::<u32, u32, ::fnv::FnvBuildHasher>::default();
+    let mut set = HashSet::<u32, ::fnv::FnvBuildHasher>::default();
+    for n in (0..5).rev() {
+        map.insert(n, n);
+        set.insert(n);
+    }
+    assert_eq!(r#"{"0":0,"1":1,"2":2,"3":3,"4":4}"#, ::serde_json::to_string(&Sorted(&map)).unwrap());
+    assert_eq!("[0,1,2,3,4]", ::serde_json::to_string(&Sorted(&set)).unwrap());
+}
@@ -0,0 +1,424 @@
+use core::fmt;
+use core::mem;
+use core::hash::{BuildHasher, Hash};
+use core::iter::{Chain, FromIterator};
+use core::ops::{BitAnd, BitOr, BitXor, Sub};
+
+use {Allocator, Bucket, Equivalent, Global, HashMap};
+
+#[cfg(test)]
+use std::string::ToString;
+
+pub struct HashSet<T, H, A: Allocator = Global> {
+    map: HashMap<T, (), H, A>
+}
+
+impl<T, H, A> Default for HashSet<T, H, A>
+    where T: Hash + Eq,
+          H: BuildHasher + Default,
+          A: Allocator + Default
+{
+    fn default() -> Self {
+        HashSet {
+            map: HashMap::default()
+        }
+    }
+}
+
+impl<T, H> HashSet<T, H>
+    where T: Hash + Eq,
+          H: BuildHasher
+{
+    pub fn with_hasher(hasher: H) -> Self {
+        Self::with_hasher_in(hasher, Global)
+    }
+
+    pub fn with_capacity(capacity: usize, hasher: H) -> Self {
+        Self::with_capacity_in(capacity, hasher, Global)
+    }
+}
+
+impl<T, H, A> HashSet<T, H, A>
+    where T: Hash + Eq,
+          H: BuildHasher,
+          A: Allocator
+{
+    pub fn with_hasher_in(hasher: H, alloc: A) -> Self {
+        HashSet {
+            map: HashMap::with_hasher_in(hasher, alloc)
+        }
+    }
+
+    pub fn with_capacity_in(capacity: usize, hasher: H, alloc: A) -> Self {
+        HashSet {
+            map: HashMap::with_capacity_in(capacity, hasher, alloc)
+        }
+    }
+
+    pub fn allocator(&self) -> &A {
+        self.map.allocator()
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    pub fn iter(&self) -> Iter<'_, T, H, A> {
+        Iter(self.map.iter())
+    }
+
+    pub fn insert(&mut self, value: T) -> bool {
+        self.map.insert(value, ()).is_none()
+    }
+
+    pub fn replace(&mut self, value: T) -> Option<T> {
+        self.map.grow_if_needed();
+        let hash = self.map.hash(&value) as usize; // NOTE: Possible panic
+        unsafe {
+            match self.map.probe_mut(hash, |k| *k == value) { // NOTE: Possible panic
+                Ok((_, pos)) => Some(mem::replace(&mut (*self.map.bucket_ptr(pos)).key, value)),
+                Err(vacancy) => {
+                    self.map.insert_vacant(hash, vacancy, Bucket::new(value, ()));
+                    None
+                }
+            }
+        }
+    }
+
+    pub fn contains<Q>(&self, value: &Q) -> bool
+        where Q: ?Sized + Hash + Equivalent<T>
+    {
+        self.map.get(value).is_some()
+    }
+
+    pub fn get<Q>(&self, value: &Q) -> Option<&T>
+        where Q: ?Sized + Hash + Equivalent<T>
+    {
+        self.map.get_key_value(value).map(|(k, _)| k)
+    }
+
+    pub fn get_or_insert(&mut self, value: T) -> &T {
+        self.map.grow_if_needed();
+        let hash = self.map.hash(&value) as usize; // NOTE: Possible panic
+        unsafe {
+            match self.map.probe_mut(hash, |k| *k == value) { // NOTE: Possible panic
+                Ok((_, pos)) => &(*self.map.bucket_ptr(pos)).key,
+                Err(vacancy) => &(*self.map.insert_vacant(hash, vacancy, Bucket::new(value, ()))).key,
+            }
+        }
+    }
+
+    // `f` has to produce a value that is equivalent to and hashes the same as `value`.
+    pub fn get_or_insert_with<Q, F>(&mut self, value: &Q, f: F) -> &T
+        where Q: ?Sized + Hash + Equivalent<T>,
+              F: FnOnce(&Q) -> T
+    {
+        self.map.grow_if_needed();
+        let hash = self.map.hash(value) as usize; // NOTE: Possible panic
+        unsafe {
+            match self.map.probe_mut(hash, |k| value.equivalent(k)) { // NOTE: Possible panic
+                Ok((_, pos)) => &(*self.map.bucket_ptr(pos)).key,
+                Err(vacancy) => &(*self.map.insert_vacant(hash, vacancy, Bucket::new(f(value), ()))).key,
+            }
+        }
+    }
+
+    pub fn remove<Q>(&mut self, value: &Q) -> bool
+        where Q: ?Sized + Hash + Equivalent<T>
+    {
+        self.map.remove(value).is_some()
+    }
+
+    pub fn take<Q>(&mut self, value: &Q) -> Option<T>
+        where Q: ?Sized + Hash + Equivalent<T>
+    {
+        self.map.remove(value).map(|(k, _)| k)
+    }
+
+    pub fn retain<F>(&mut self, mut f: F)
+        where F: FnMut(&T) -> bool
+    {
+        self.map.retain(|k, _| f(k));
+    }
+
+    pub fn clear(&mut self) {
+        self.map.clear();
+    }
+
+    pub fn union<'a>(&'a self, other: &'a HashSet<T, H, A>) -> Union<'a, T, H, A> {
+        Union(self.iter().chain(other.difference(self)))
+    }
+
+    pub fn intersection<'a>(&'a self, other: &'a HashSet<T, H, A>) -> Intersection<'a, T, H, A> {
+        let (smaller, larger) = if self.len() <= other.len() {
+            (self, other)
+        } else {
+            (other, self)
+        };
+        Intersection {
+            iter: smaller.iter(),
+            other: larger
+        }
+    }
+
+    pub fn difference<'a>(&'a self, other: &'a HashSet<T, H, A>) -> Difference<'a, T, H, A> {
+        Difference {
+            iter: self.iter(),
+            other
+        }
+    }
+
+    pub fn symmetric_difference<'a>(&'a self, other: &'a HashSet<T, H, A>) -> SymmetricDifference<'a, T, H, A> {
+        SymmetricDifference(self.difference(other).chain(other.difference(self)))
+    }
+
+    pub fn is_disjoint(&self, other: &HashSet<T, H, A>) -> bool {
+        self.intersection(other).next().is_none()
+    }
+
+    pub fn is_subset(&self, other: &HashSet<T, H, A>) -> bool {
+        self.len() <= other.len() && self.iter().all(|v| other.contains(v))
+    }
+
+    pub fn is_superset(&self, other: &HashSet<T, H, A>) -> bool {
+        other.is_subset(self)
+    }
+}
+
+impl<T, H, A: Allocator> PartialEq for HashSet<T, H, A>
+    where T: Hash + Eq,
+          H: BuildHasher
+{
+    fn eq(&self, other: &HashSet<T, H, A>) -> bool {
+        self.len() == other.len() && self.is_subset(other)
+    }
+}
+
+impl<T, H, A: Allocator> Eq for HashSet<T, H, A>
+    where T: Hash + Eq,
+          H: BuildHasher
+{}
+
+impl<T, H, A: Allocator> fmt::Debug for HashSet<T, H, A>
+    where T: Hash + Eq + fmt::Debug,
+          H: BuildHasher
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_set().entries(self.iter()).finish()
+    }
+}
+
+impl<T, H, A: Allocator> Extend<T> for HashSet<T, H, A>
+    where T: Hash + Eq,
+          H: BuildHasher
+{
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        for value in iter {
+            self.insert(value);
+        }
+    }
+}
+
+impl<T, H, A: Allocator + Default> FromIterator<T> for HashSet<T, H, A>
+    where T: Hash + Eq,
+          H: BuildHasher + Default
+{
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        let mut set = Self::default();
+        set.extend(iter);
+        set
+    }
+}
+
+impl<'b, T, H, A: Allocator + Default> BitOr<&'b HashSet<T, H, A>> for &HashSet<T, H, A>
+    where T: Hash + Eq + Clone,
+          H: BuildHasher + Default
+{
+    type Output = HashSet<T, H, A>;
+
+    fn bitor(self, rhs: &'b HashSet<T, H, A>) -> HashSet<T, H, A> {
+        self.union(rhs).cloned().collect()
+    }
+}
+
+impl<'b, T, H, A: Allocator + Default> BitAnd<&'b HashSet<T, H, A>> for &HashSet<T, H, A>
+    where T: Hash + Eq + Clone,
+          H: BuildHasher + Default
+{
+    type Output = HashSet<T, H, A>;
+
+    fn bitand(self, rhs: &'b HashSet<T, H, A>) -> HashSet<T, H, A> {
+        self.intersection(rhs).cloned().collect()
+    }
+}
+
+impl<'b, T, H, A: Allocator + Default> Sub<&'b HashSet<T, H, A>> for &HashSet<T, H, A>
+    where T: Hash + Eq + Clone,
+          H: BuildHasher + Default
+{
+    type Output = HashSet<T, H, A>;
+
+    fn sub(self, rhs: &'b HashSet<T, H, A>) -> HashSet<T, H, A> {
+        self.difference(rhs).cloned().collect()
+    }
+}
+
+impl<'b, T, H, A: Allocator + Default> BitXor<&'b HashSet<T, H, A>> for &HashSet<T, H, A>
+    where T: Hash + Eq + Clone,
+          H: BuildHasher + Default
+{
+    type Output = HashSet<T, H, A>;
+
+    fn bitxor(self, rhs: &'b HashSet<T, H, A>) -> HashSet<T, H, A> {
+        self.symmetric_difference(rhs).cloned().collect()
+    }
+}
+
+impl<'a, T: 'a, H: 'a, A: Allocator + 'a> IntoIterator for &'a HashSet<T, H, A>
+    where T: Hash + Eq,
+          H: BuildHasher
+{
+    type IntoIter = Iter<'a, T, H, A>;
+    type Item = &'a T;
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+pub struct Iter<'a, T: 'a, H: 'a, A: Allocator + 'a = Global>(::Iter<'a, T, (), H, A>);
+
+impl<'a, T: 'a, H: 'a, A: Allocator + 'a> Iterator for Iter<'a, T, H, A>
+    where T: Hash + Eq,
+          H: BuildHasher
+{
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next().map(|(k, _)| k)
+    }
+}
+
+pub struct Union<'a, T: 'a, H: 'a, A: Allocator + 'a = Global>(Chain<Iter<'a, T, H, A>, Difference<'a, T, H, A>>);
+
+impl<'a, T: 'a, H: 'a, A: Allocator + 'a> Iterator for Union<'a, T, H, A>
+    where T: Hash + Eq,
+          H: BuildHasher
+{
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next()
+    }
+}
+
+pub struct Intersection<'a, T: 'a, H: 'a, A: Allocator + 'a = Global> {
+    iter: Iter<'a, T, H, A>,
+    other: &'a HashSet<T, H, A>
+}
+
+impl<'a, T: 'a, H: 'a, A: Allocator + 'a> Iterator for Intersection<'a, T, H, A>
+    where T: Hash + Eq,
+          H: BuildHasher
+{
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let other = self.other;
+        self.iter.find(|v| other.contains(*v))
+    }
+}
+
+pub struct Difference<'a, T: 'a, H: 'a, A: Allocator + 'a = Global> {
+    iter: Iter<'a, T, H, A>,
+    other: &'a HashSet<T, H, A>
+}
+
+impl<'a, T: 'a, H: 'a, A: Allocator + 'a> Iterator for Difference<'a, T, H, A>
+    where T: Hash + Eq,
+          H: BuildHasher
+{
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let other = self.other;
+        self.iter.find(|v| !other.contains(*v))
+    }
+}
+
+pub struct SymmetricDifference<'a, T: 'a, H: 'a, A: Allocator + 'a = Global>(Chain<Difference<'a, T, H, A>, Difference<'a, T, H, A>>);
+
+impl<'a, T: 'a, H: 'a, A: Allocator + 'a> Iterator for SymmetricDifference<'a, T, H, A>
+    where T: Hash + Eq,
+          H: BuildHasher
+{
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next()
+    }
+}
+
+#[cfg(test)]
+fn set_of(values: ::std::ops::Range<u32>) -> HashSet<u32, ::fnv::FnvBuildHasher> {
+    values.collect()
+}
+
+#[test]
+fn set_inserting_and_removing_works() {
+    let max = 10000;
+    let mut set = HashSet::with_hasher(::fnv::FnvBuildHasher::default());
+    for n in 0..max {
+        assert!(set.insert(n));
+        assert!(!set.insert(n));
+    }
+    assert_eq!(max as usize, set.len());
+    for n in 0..max {
+        assert!(set.contains(&n));
+        assert_eq!(Some(&n), set.get(&n));
+    }
+    for n in 0..max / 2 {
+        assert!(set.remove(&n));
+        assert_eq!(None, set.take(&n));
+    }
+    for n in max / 2..max {
+        assert_eq!(Some(n), set.take(&n));
+    }
+    assert!(set.is_empty());
+}
+
+#[test]
+fn set_replacing_works() {
+    let mut set = HashSet::with_hasher(::fnv::FnvBuildHasher::default());
+    assert_eq!(None, set.replace("a".to_string()));
+    assert_eq!(Some("a".to_string()), set.replace("a".to_string()));
+    assert_eq!("b", set.get_or_insert_with("b", str::to_string));
+    assert_eq!("b", set.get_or_insert_with("b", |_| unreachable!()));
+    assert_eq!("c", set.get_or_insert("c".to_string()));
+    assert_eq!(3, set.len());
+}
+
+#[test]
+fn set_algebra_works() {
+    let a = set_of(0..100);
+    let b = set_of(50..150);
+
+    assert_eq!(set_of(0..150), &a | &b);
+    assert_eq!(set_of(50..100), &a & &b);
+    assert_eq!(set_of(0..50), &a - &b);
+    assert_eq!(&set_of(0..50) | &set_of(100..150), &a ^ &b);
+
+    assert_eq!(150, a.union(&b).count());
+    assert_eq!(50, a.intersection(&b).count());
+    assert_eq!(50, a.difference(&b).count());
+    assert_eq!(100, a.symmetric_difference(&b).count());
+
+    assert!(set_of(10..20).is_subset(&a));
+    assert!(a.is_superset(&set_of(10..20)));
+    assert!(!a.is_subset(&b));
+    assert!(set_of(0..50).is_disjoint(&set_of(50..100)));
+    assert!(!a.is_disjoint(&b));
+}
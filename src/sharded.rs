@@ -0,0 +1,214 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::hash::{BuildHasher, Hash};
+use core::ops::{Deref, DerefMut};
+use core::slice;
+
+use std::sync::{PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::thread;
+
+use {Entry, Equivalent, HashMap};
+
+// Shards are chosen by the top bits of the hash, while the shards themselves use the low bits
+// to find cells. Keys are hashed again by the shard they land in, since lookups can only trust
+// hashes from the shard's own hasher.
+// Maps stay intact when user code panics, so locks poisoned by a panic are used as they are.
+pub struct ShardedMap<K, V, H> {
+    shards: Box<[RwLock<HashMap<K, V, H>>]>,
+    hasher: H,
+    shift: u32
+}
+
+impl<K, V, H> Default for ShardedMap<K, V, H>
+    where K: Hash + PartialEq,
+          H: BuildHasher + Clone + Default
+{
+    fn default() -> Self {
+        Self::with_hasher(H::default())
+    }
+}
+
+impl<K, V, H> ShardedMap<K, V, H>
+    where K: Hash + PartialEq,
+          H: BuildHasher + Clone
+{
+    // Uses four shards per available thread.
+    pub fn with_hasher(hasher: H) -> Self {
+        let threads = thread::available_parallelism().map_or(1, |threads| threads.get());
+        Self::with_shards(4 * threads.next_power_of_two(), hasher)
+    }
+
+    pub fn with_shards(shards: usize, hasher: H) -> Self {
+        assert!(shards.is_power_of_two(), "number of shards has to be a power of two");
+        ShardedMap {
+            shards: (0..shards).map(|_| RwLock::new(HashMap::with_hasher(hasher.clone()))).collect::<Vec<_>>().into_boxed_slice(),
+            hasher,
+            shift: 64 - shards.trailing_zeros()
+        }
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<ReadGuard<'_, K, V, H>>
+        where Q: ?Sized + Hash + Equivalent<K>
+    {
+        let guard = self.shard(key).read().unwrap_or_else(PoisonError::into_inner);
+        let value = guard.get(key)? as *const V; // NOTE: Possible panic
+        Some(ReadGuard {
+            _guard: guard,
+            value
+        })
+    }
+
+    pub fn get_mut<Q>(&self, key: &Q) -> Option<WriteGuard<'_, K, V, H>>
+        where Q: ?Sized + Hash + Equivalent<K>
+    {
+        let mut guard = self.shard(key).write().unwrap_or_else(PoisonError::into_inner);
+        let value = guard.get_mut(key)? as *mut V; // NOTE: Possible panic
+        Some(WriteGuard {
+            _guard: guard,
+            value
+        })
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+        where Q: ?Sized + Hash + Equivalent<K>
+    {
+        self.get(key).is_some()
+    }
+
+    pub fn insert(&self, key: K, value: V) -> Option<(K, V)> {
+        let mut guard = self.shard(&key).write().unwrap_or_else(PoisonError::into_inner);
+        guard.insert(key, value) // NOTE: Possible panic
+    }
+
+    pub fn remove<Q>(&self, key: &Q) -> Option<(K, V)>
+        where Q: ?Sized + Hash + Equivalent<K>
+    {
+        let mut guard = self.shard(key).write().unwrap_or_else(PoisonError::into_inner);
+        guard.remove(key) // NOTE: Possible panic
+    }
+
+    // Gives the entry of the key to `f` while its shard is locked for writing,
+    // e.g. `map.entry(key, |entry| *entry.or_insert(0) += 1)`.
+    pub fn entry<F, R>(&self, key: K, f: F) -> R
+        where F: FnOnce(Entry<'_, K, V, H>) -> R
+    {
+        let mut guard = self.shard(&key).write().unwrap_or_else(PoisonError::into_inner);
+        f(guard.entry(key)) // NOTE: Possible panic
+    }
+
+    // Shards are locked one after another, so the count can be off when other threads are
+    // modifying the map at the same time.
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.read().unwrap_or_else(PoisonError::into_inner).len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shards.iter().all(|shard| shard.read().unwrap_or_else(PoisonError::into_inner).is_empty())
+    }
+
+    // Locking the shards is up to the caller, so that every entry can be visited without
+    // stopping the whole map.
+    pub fn shards(&self) -> slice::Iter<'_, RwLock<HashMap<K, V, H>>> {
+        self.shards.iter()
+    }
+
+    pub fn hasher(&self) -> &H {
+        &self.hasher
+    }
+
+    fn shard<Q: ?Sized + Hash>(&self, key: &Q) -> &RwLock<HashMap<K, V, H>> {
+        let hash = self.hasher.hash_one(key); // NOTE: Possible panic
+        &self.shards[hash.checked_shr(self.shift).unwrap_or(0) as usize]
+    }
+}
+
+pub struct ReadGuard<'a, K: 'a, V: 'a, H: 'a> {
+    _guard: RwLockReadGuard<'a, HashMap<K, V, H>>,
+    value: *const V
+}
+
+impl<'a, K: 'a, V: 'a, H: 'a> Deref for ReadGuard<'a, K, V, H> {
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        unsafe { &*self.value }
+    }
+}
+
+pub struct WriteGuard<'a, K: 'a, V: 'a, H: 'a> {
+    _guard: RwLockWriteGuard<'a, HashMap<K, V, H>>,
+    value: *mut V
+}
+
+impl<'a, K: 'a, V: 'a, H: 'a> Deref for WriteGuard<'a, K, V, H> {
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        unsafe { &*self.value }
+    }
+}
+
+impl<'a, K: 'a, V: 'a, H: 'a> DerefMut for WriteGuard<'a, K, V, H> {
+    fn deref_mut(&mut self) -> &mut V {
+        unsafe { &mut *self.value }
+    }
+}
+
+#[test]
+fn sharded_map_works() {
+    let map = ShardedMap::with_shards(8, ::fnv::FnvBuildHasher::default());
+    for n in 0..1000u32 {
+        assert_eq!(None, map.insert(n, n));
+    }
+    assert_eq!(1000, map.len());
+    assert_eq!(Some((5, 5)), map.insert(5, 6));
+    assert_eq!(6, *map.get(&5).unwrap());
+    *map.get_mut(&5).unwrap() += 1;
+    assert_eq!(7, *map.get(&5).unwrap());
+    assert!(map.get(&1000).is_none() && map.get_mut(&1000).is_none());
+    assert_eq!(Some((5, 7)), map.remove(&5));
+    assert!(!map.contains_key(&5));
+    map.entry(5, |entry| *entry.or_insert(0) += 10);
+    map.entry(5, |entry| *entry.or_insert(0) += 10);
+    assert_eq!(20, *map.get(&5).unwrap());
+    assert!(map.shards().all(|shard| !shard.read().unwrap().is_empty()));
+    assert_eq!(1000, map.shards().map(|shard| shard.read().unwrap().iter().count()).sum::<usize>());
+
+    let single = ShardedMap::with_shards(1, ::fnv::FnvBuildHasher::default());
+    single.insert(1, 1);
+    assert_eq!(1, *single.get(&1).unwrap());
+}
+
+#[test]
+fn sharded_map_stress_works() {
+    use std::sync::Arc;
+
+    let threads = 16;
+    let rounds = 2000u64;
+    let map = Arc::new(ShardedMap::with_shards(16, ::fnv::FnvBuildHasher::default()));
+    let handles = (0..threads)
+        .map(|thread| {
+            let map = map.clone();
+            thread::spawn(move || {
+                for n in 0..rounds {
+                    // Every thread bumps the same shared counters and owns a set of private keys.
+                    map.entry(n % 100, |entry| *entry.or_insert(0) += 1);
+                    let own = ((thread + 1) << 32) | n;
+                    map.insert(own, n);
+                    assert_eq!(n, *map.get(&own).unwrap());
+                    if n % 2 == 0 {
+                        assert_eq!(Some((own, n)), map.remove(&own));
+                    }
+                    let _ = map.get(&((((thread + 2) % threads) << 32) | n)).map(|v| *v);
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    for n in 0..100 {
+        assert_eq!(threads * rounds / 100, *map.get(&n).unwrap());
+    }
+    assert_eq!(100 + (threads * rounds / 2) as usize, map.len());
+}
@@ -0,0 +1,101 @@
+// Scans the metadata of a whole cell at once. Every cell has exactly as many metadata bytes as
+// fit into a SSE2 or NEON register, and other targets fall back to checking them one by one.
+#[cfg(all(any(target_arch = "x86", target_arch = "x86_64"), target_feature = "sse2"))]
+pub(crate) fn empty_mask(bytes: &[u8; 16]) -> u16 {
+    #[cfg(target_arch = "x86")]
+    use core::arch::x86::*;
+    #[cfg(target_arch = "x86_64")]
+    use core::arch::x86_64::*;
+
+    unsafe {
+        let group = _mm_loadu_si128(bytes.as_ptr() as *const __m128i);
+        _mm_movemask_epi8(_mm_cmpeq_epi8(group, _mm_set1_epi8(-1))) as u16
+    }
+}
+
+#[cfg(all(target_arch = "aarch64", target_feature = "neon"))]
+pub(crate) fn empty_mask(bytes: &[u8; 16]) -> u16 {
+    use core::arch::aarch64::*;
+
+    // NEON has no movemask, so each lane keeps only its own bit and the halves are summed up.
+    const BITS: [u8; 16] = [1, 2, 4, 8, 16, 32, 64, 128, 1, 2, 4, 8, 16, 32, 64, 128];
+    unsafe {
+        let empty = vceqq_u8(vld1q_u8(bytes.as_ptr()), vdupq_n_u8(0xFF));
+        let bits = vandq_u8(empty, vld1q_u8(BITS.as_ptr()));
+        vaddv_u8(vget_low_u8(bits)) as u16 | (vaddv_u8(vget_high_u8(bits)) as u16) << 8
+    }
+}
+
+#[cfg(not(any(
+    all(any(target_arch = "x86", target_arch = "x86_64"), target_feature = "sse2"),
+    all(target_arch = "aarch64", target_feature = "neon"),
+)))]
+pub(crate) fn empty_mask(bytes: &[u8; 16]) -> u16 {
+    portable_empty_mask(bytes)
+}
+
+// Only tested against on targets with SIMD
+#[allow(dead_code)]
+fn portable_empty_mask(bytes: &[u8; 16]) -> u16 {
+    bytes.iter()
+        .enumerate()
+        .fold(0, |mask, (slot, &byte)| mask | ((byte == 0xFF) as u16) << slot)
+}
+
+// Hints that memory at the pointer is about to be read. Does nothing on other targets.
+#[inline]
+pub(crate) fn prefetch<T>(ptr: *const T) {
+    #[cfg(all(any(target_arch = "x86", target_arch = "x86_64"), target_feature = "sse"))]
+    unsafe {
+        #[cfg(target_arch = "x86")]
+        use core::arch::x86::{_mm_prefetch, _MM_HINT_T0};
+        #[cfg(target_arch = "x86_64")]
+        use core::arch::x86_64::{_mm_prefetch, _MM_HINT_T0};
+
+        _mm_prefetch::<_MM_HINT_T0>(ptr as *const i8);
+    }
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        ::core::arch::asm!("prfm pldl1keep, [{}]", in(reg) ptr, options(nostack, readonly, preserves_flags));
+    }
+    #[cfg(not(any(all(any(target_arch = "x86", target_arch = "x86_64"), target_feature = "sse"), target_arch = "aarch64")))]
+    let _ = ptr;
+}
+
+// Iterates positions of the set bits from the lowest one.
+pub(crate) struct BitMask(pub(crate) u16);
+
+impl Iterator for BitMask {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        if self.0 == 0 {
+            return None;
+        }
+        let slot = self.0.trailing_zeros() as usize;
+        self.0 &= self.0 - 1;
+        Some(slot)
+    }
+}
+
+#[test]
+fn masks_match_portable_ones() {
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::SmallRng;
+
+    let mut rng = SmallRng::from_seed([7; 16]);
+    for _ in 0..10000 {
+        let mut bytes = [0; 16];
+        for byte in &mut bytes {
+            *byte = if rng.gen() { 0xFF } else { rng.gen() };
+        }
+        let mask = empty_mask(&bytes);
+        assert_eq!(portable_empty_mask(&bytes), mask);
+        assert_eq!(bytes.iter().filter(|&&b| b == 0xFF).count(), BitMask(mask).count());
+        for slot in BitMask(mask) {
+            assert_eq!(0xFF, bytes[slot]);
+        }
+    }
+    assert_eq!(0xFFFF, empty_mask(&[0xFF; 16]));
+    assert_eq!(0, empty_mask(&[0x7F; 16]));
+}
@@ -0,0 +1,120 @@
+use alloc::vec::Vec;
+use alloc::vec;
+
+use core::hash::{BuildHasher, Hash};
+
+use {cell_data, Allocator, Cell, HashMap, BLOCK_SIZE, JUMP_DISTANCES};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Stats {
+    pub size: usize,
+    // Number of items that fit before the map grows
+    pub capacity: usize,
+    pub cells: usize,
+    // Share of slots that are in use
+    pub load_factor: f32,
+    // Number of chains of each length, indexed by the length
+    pub chain_lengths: Vec<usize>,
+    // How many links between chain members use each index of the jump distance table
+    pub jumps: Vec<usize>,
+    // Keys compared when looking up a key that is present
+    pub average_probe: f32,
+    pub max_probe: usize,
+    // Entries that are not in their home slot
+    pub displaced: usize,
+    // Number of cells with each amount of used slots, indexed by the amount
+    pub cell_occupancy: Vec<usize>,
+}
+
+impl<K, V, H, A> HashMap<K, V, H, A>
+    where K: Hash + PartialEq,
+          H: BuildHasher,
+          A: Allocator
+{
+    // Walks every chain, so this takes time linear in the capacity.
+    pub fn stats(&self) -> Stats {
+        let mut stats = Stats {
+            size: self.len(),
+            capacity: self.capacity(),
+            cells: self.cell_count(),
+            load_factor: self.len() as f32 / (BLOCK_SIZE * self.cell_count()) as f32,
+            chain_lengths: Vec::new(),
+            jumps: vec![0; JUMP_DISTANCES.len()],
+            average_probe: 0.,
+            max_probe: 0,
+            displaced: 0,
+            cell_occupancy: vec![0; BLOCK_SIZE + 1],
+        };
+        let mut probes = 0;
+        unsafe {
+            collect_stats(&mut stats, &mut probes, self.ptr, self.capacity);
+            if let Some(ref old) = self.old {
+                collect_stats(&mut stats, &mut probes, old.ptr, old.capacity);
+            }
+        }
+        if stats.size > 0 {
+            stats.average_probe = probes as f32 / stats.size as f32;
+        }
+        stats
+    }
+}
+
+unsafe fn collect_stats<K, V>(stats: &mut Stats, probes: &mut usize, ptr: *mut Cell<K, V>, capacity: usize) {
+    for cell in 0..capacity {
+        let cur_cell = &*ptr.add(cell);
+        stats.cell_occupancy[cur_cell.meta.full_mask().count_ones() as usize] += 1;
+        for slot in 0..BLOCK_SIZE {
+            let meta = &cur_cell.meta.0[slot];
+            if meta.is_empty() || meta.is_storage() {
+                continue;
+            }
+            let mut pos = cell * BLOCK_SIZE + slot;
+            let mut length = 1;
+            loop {
+                *probes += length;
+                let jump = (*cell_data(ptr, capacity, pos).0).jump_length();
+                if jump == 0 {
+                    break;
+                }
+                stats.jumps[jump as usize] += 1;
+                pos = pos.wrapping_add(JUMP_DISTANCES[jump as usize]);
+                length += 1;
+            }
+            if stats.chain_lengths.len() <= length {
+                stats.chain_lengths.resize(length + 1, 0);
+            }
+            stats.chain_lengths[length] += 1;
+            stats.max_probe = stats.max_probe.max(length);
+            stats.displaced += length - 1;
+        }
+    }
+}
+
+#[test]
+fn stats_work() {
+    let max = 1000;
+    let mut good = HashMap::with_hasher(::fnv::FnvBuildHasher::default());
+    let mut bad = HashMap::with_hasher(::BuildColliding::default());
+    for n in 0..max {
+        good.insert(n, n);
+        bad.insert(n, n);
+    }
+    for map_stats in &[good.stats(), bad.stats()] {
+        let chains = map_stats.chain_lengths.iter().sum::<usize>();
+        let chained = map_stats.chain_lengths.iter().enumerate().map(|(length, count)| length * count).sum::<usize>();
+        let occupied = map_stats.cell_occupancy.iter().enumerate().map(|(used, count)| used * count).sum::<usize>();
+        assert_eq!(max, map_stats.size);
+        assert_eq!(max, chained);
+        assert_eq!(max, occupied);
+        assert_eq!(map_stats.cells, map_stats.cell_occupancy.iter().sum::<usize>());
+        assert_eq!(max - chains, map_stats.displaced);
+        assert_eq!(max - chains, map_stats.jumps.iter().sum::<usize>());
+        assert_eq!(0, map_stats.jumps[0]);
+        assert!(map_stats.average_probe >= 1. && map_stats.average_probe <= map_stats.max_probe as f32);
+        assert!(map_stats.load_factor > 0. && map_stats.load_factor <= 1.);
+    }
+    let bad_stats = bad.stats();
+    assert_eq!(64, bad_stats.chain_lengths.iter().sum::<usize>());
+    assert!(bad_stats.max_probe > good.stats().max_probe);
+    assert_eq!(HashMap::<u32, u32, ::fnv::FnvBuildHasher>::default().stats().average_probe, 0.);
+}
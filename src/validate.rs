@@ -0,0 +1,189 @@
+use alloc::vec;
+use core::fmt;
+use core::hash::{BuildHasher, Hash};
+
+use builder::Policy;
+use {cell_data, split_hash, stored_hash, Allocator, Cell, HashMap, BLOCK_SIZE, JUMP_DISTANCES};
+
+// Slot positions count the cells still being migrated after the current ones.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum InvariantError {
+    // Key does not hash to the head of the chain it was found in
+    WrongChain { pos: usize, head: usize },
+    // Jump index beyond the ones the map is allowed to use
+    JumpOutOfRange { pos: usize, jump: u8 },
+    // Chain continues into an empty slot or into the head of another chain
+    BrokenChain { pos: usize },
+    // Storage slot is reached from more than one chain, or twice from the same one
+    SharedStorage { pos: usize },
+    // Storage slot is not reached from any chain head
+    OrphanedStorage { pos: usize },
+    StoredHashMismatch { pos: usize },
+    SizeMismatch { size: usize, occupied: usize },
+}
+
+impl fmt::Display for InvariantError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            InvariantError::WrongChain { pos, head } => write!(fmt, "key in slot {} does not belong to chain starting at {}", pos, head),
+            InvariantError::JumpOutOfRange { pos, jump } => write!(fmt, "slot {} has jump index {} out of range", pos, jump),
+            InvariantError::BrokenChain { pos } => write!(fmt, "chain continues into slot {} that is not storage", pos),
+            InvariantError::SharedStorage { pos } => write!(fmt, "storage slot {} is reached more than once", pos),
+            InvariantError::OrphanedStorage { pos } => write!(fmt, "storage slot {} is not part of any chain", pos),
+            InvariantError::StoredHashMismatch { pos } => write!(fmt, "stored hash of slot {} does not match its key", pos),
+            InvariantError::SizeMismatch { size, occupied } => write!(fmt, "size is {} but {} slots are occupied", size, occupied),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl ::std::error::Error for InvariantError {}
+
+impl<K, V, H, A> HashMap<K, V, H, A>
+    where K: Hash + PartialEq,
+          H: BuildHasher,
+          A: Allocator
+{
+    // Walks every chain of the table and checks that it is well formed. Meant for tests and
+    // fuzzing, as it takes time and memory linear in the capacity and hashes every key.
+    pub fn validate(&self) -> Result<(), InvariantError> {
+        unsafe {
+            check_cells(self.ptr, self.capacity, self.size, 0, &self.policy, |key| self.hash(key) as usize)?;
+            if let Some(ref old) = self.old {
+                check_cells(old.ptr, old.capacity, old.size, self.capacity * BLOCK_SIZE, &self.policy, |key| self.hash(key) as usize)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+unsafe fn check_cells<K, V, F>(ptr: *mut Cell<K, V>, capacity: usize, size: usize, base: usize, policy: &Policy, hash: F) -> Result<(), InvariantError>
+    where F: Fn(&K) -> usize
+{
+    let mut reached = vec![false; capacity * BLOCK_SIZE];
+    let mut occupied = 0;
+    for head in 0..capacity * BLOCK_SIZE {
+        let meta = &*cell_data(ptr, capacity, head).0;
+        if meta.is_empty() {
+            continue;
+        }
+        occupied += 1;
+        if meta.is_storage() {
+            continue;
+        }
+        let mut pos = head;
+        loop {
+            let (meta, data_ptr) = cell_data(ptr, capacity, pos);
+            let key_hash = hash(&(*data_ptr).key);
+            if split_hash(key_hash, capacity) != split_hash(head, capacity) {
+                return Err(InvariantError::WrongChain { pos: base + pos, head: base + head });
+            }
+            match stored_hash(ptr, capacity, policy.store_hashes, pos) {
+                Some(stored) if stored != key_hash => return Err(InvariantError::StoredHashMismatch { pos: base + pos }),
+                _ => {},
+            }
+            let jump = (*meta).jump_length();
+            if jump == 0 {
+                break;
+            }
+            if jump > policy.max_jump || jump as usize >= JUMP_DISTANCES.len() {
+                return Err(InvariantError::JumpOutOfRange { pos: base + pos, jump });
+            }
+            pos = pos.wrapping_add(JUMP_DISTANCES[jump as usize]) & (capacity * BLOCK_SIZE - 1);
+            let next = &*cell_data(ptr, capacity, pos).0;
+            if next.is_empty() || !next.is_storage() {
+                return Err(InvariantError::BrokenChain { pos: base + pos });
+            }
+            if reached[pos] {
+                return Err(InvariantError::SharedStorage { pos: base + pos });
+            }
+            reached[pos] = true;
+        }
+    }
+    for (pos, &reached) in reached.iter().enumerate() {
+        let meta = &*cell_data(ptr, capacity, pos).0;
+        if !meta.is_empty() && meta.is_storage() && !reached {
+            return Err(InvariantError::OrphanedStorage { pos: base + pos });
+        }
+    }
+    if occupied != size {
+        return Err(InvariantError::SizeMismatch { size, occupied });
+    }
+    Ok(())
+}
+
+#[test]
+fn validation_detects_corruption() {
+    let mut map = ::HashMapBuilder::new(::fnv::FnvBuildHasher::default())
+        .max_jump_index(20)
+        .build();
+    for n in 0..1000 {
+        map.insert(n, n);
+    }
+    assert_eq!(Ok(()), map.validate());
+
+    map.size += 1;
+    assert_eq!(Err(InvariantError::SizeMismatch { size: 1001, occupied: 1000 }), map.validate());
+    map.size -= 1;
+
+    unsafe {
+        let head = (0..map.capacity * BLOCK_SIZE)
+            .find(|&pos| {
+                let meta = &*cell_data(map.ptr, map.capacity, pos).0;
+                !meta.is_empty() && !meta.is_storage()
+            })
+            .unwrap();
+        let meta = &mut *cell_data(map.ptr, map.capacity, head).0;
+        let jump = meta.jump_length();
+        meta.set_jump(21);
+        assert_eq!(Err(InvariantError::JumpOutOfRange { pos: head, jump: 21 }), map.validate());
+        meta.set_jump(jump);
+    }
+    assert_eq!(Ok(()), map.validate());
+}
+
+// Replays random operations against the standard library map, checking the table after each one.
+#[test]
+fn model_based_operations_work() {
+    use rand::rngs::SmallRng;
+    use rand::{Rng, SeedableRng};
+    use std::collections::HashMap as StdHashMap;
+
+    fn replay<H: BuildHasher>(mut map: HashMap<u32, u32, H>, seed: u8) {
+        let mut rng = SmallRng::from_seed([seed; 16]);
+        let mut model = StdHashMap::new();
+        for step in 0..3000 {
+            let key = rng.gen_range(0, 200);
+            match rng.gen_range(0, 20) {
+                0..=8 => assert_eq!(model.insert(key, step).map(|v| (key, v)), map.insert(key, step)),
+                9..=14 => assert_eq!(model.remove(&key).map(|v| (key, v)), map.remove(&key)),
+                15..=17 => assert_eq!(model.get(&key), map.get(&key)),
+                18 => {
+                    map.retain(|k, _| k % 5 != 0);
+                    model.retain(|k, _| k % 5 != 0);
+                },
+                _ => map.shrink_to_fit(),
+            }
+            if let Err(error) = map.validate() {
+                panic!("{} after step {} seed {}", error, step, seed);
+            }
+            assert_eq!(model.len(), map.len());
+        }
+        for (k, v) in &model {
+            assert_eq!(Some(v), map.get(k));
+        }
+    }
+
+    for seed in 1..20 {
+        for &store_hashes in &[false, true] {
+            for &incremental in &[false, true] {
+                let builder = ::HashMapBuilder::new(::fnv::FnvBuildHasher::default()).store_hashes(store_hashes);
+                let builder = if incremental { builder.incremental_resize(1).max_jump_index(30) } else { builder };
+                replay(builder.build(), seed);
+                let builder = ::HashMapBuilder::new(::BuildColliding::default()).store_hashes(store_hashes);
+                let builder = if incremental { builder.incremental_resize(1) } else { builder };
+                replay(builder.build(), seed);
+            }
+        }
+    }
+}